@@ -13,8 +13,8 @@ use miette::{IntoDiagnostic, Result};
 
 use serde::{Deserialize, Serialize};
 use sn_dbc::{
-    rand::RngCore, rng, KeyImage, KeyManager, RingCtTransaction, SimpleKeyManager, SimpleSigner,
-    SpentBookNodeMock, SpentProofShare,
+    rand::RngCore, rng, DecoyInput, KeyImage, KeyManager, RingCtTransaction, SimpleKeyManager,
+    SimpleSigner, SpentBookNodeMock, SpentProofShare,
 };
 use sn_dbc_examples::wire;
 
@@ -29,6 +29,9 @@ use std::io::Write;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 
+/// Most decoys returned for a single request.
+const MAX_DECOYS: usize = 100;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpentLogEntry {
     key_image: KeyImage,
@@ -184,6 +187,11 @@ impl SpentbookNodeServer {
                                         self.handle_log_spent_request(k, t).await,
                                     )
                                 }
+                                wire::spentbook::wallet::request::Msg::Decoys(count) => {
+                                    wire::spentbook::wallet::reply::Msg::Decoys(
+                                        self.handle_decoys_request(count, &mut rng),
+                                    )
+                                }
                                 wire::spentbook::wallet::request::Msg::Discover => {
                                     wire::spentbook::wallet::reply::Msg::Discover(
                                         match self.spentbook_node.as_ref() {
@@ -236,6 +244,21 @@ impl SpentbookNodeServer {
         }
     }
 
+    /// Returns up to count random outputs from the spentbook log, for
+    /// wallets to use as decoys.  Fewer are returned while the log is small.
+    fn handle_decoys_request(
+        &self,
+        count: usize,
+        rng: &mut impl RngCore,
+    ) -> wire::spentbook::wallet::Result<Vec<DecoyInput>> {
+        if let Some(spentbook_node) = self.spentbook_node.as_ref() {
+            Ok(spentbook_node.random_decoys(count.min(MAX_DECOYS), rng))
+        } else {
+            debug!("ignoring decoys request because spentbook_node not yet created.");
+            Err(wire::spentbook::wallet::Error::NotReady)
+        }
+    }
+
     async fn append_spent_log(&self, key_image: KeyImage, tx: RingCtTransaction) -> Result<()> {
         use std::fs::OpenOptions;
         let mut file = OpenOptions::new()
@@ -11,6 +11,8 @@ use log::debug;
 use miette::{miette, IntoDiagnostic, Result};
 // use serde::{Deserialize, Serialize};
 use bls_dkg::PublicKeySet;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
@@ -22,21 +24,23 @@ use xor_name::XorName;
 
 use sn_dbc::{
     blsttc::{serde_impl::SerdeSecret, PublicKey, SecretKey, SecretKeySet},
-    rng, Amount, AmountSecrets, Dbc, GenesisMaterial, KeyImage, KeyManager, Owner, OwnerOnce,
-    RingCtTransaction, SimpleKeyManager, SimpleSigner, SpentProofShare, TransactionBuilder,
+    rand::{seq::SliceRandom, RngCore},
+    rng, Amount, AmountSecrets, Dbc, DbcBuilder, DecoyInput, G1Affine, GenesisMaterial, KeyImage,
+    KeyManager, OutputOwnerMap, Owner, OwnerOnce, RevealedCommitment, RingCtTransaction,
+    SimpleKeyManager, SimpleSigner, SpentProofShare, TransactionBuilder, TransactionVerifier,
 };
 
 use qp2p::{self, Config, Endpoint};
 use structopt::StructOpt;
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{btree_map, hash_map, BTreeMap, BTreeSet, HashMap};
 use std::net::{Ipv4Addr, SocketAddr};
 
 #[cfg(unix)]
 use std::os::unix::{io::AsRawFd, prelude::RawFd};
 
 #[cfg(unix)]
-use termios::{tcsetattr, Termios, ICANON, TCSADRAIN};
+use termios::{tcsetattr, Termios, ECHO, ICANON, TCSADRAIN};
 
 /// Configuration for the program
 #[derive(StructOpt)]
@@ -48,14 +52,51 @@ pub struct WalletNodeConfig {
     #[structopt(long, parse(from_os_str), default_value = ".wallet.dat")]
     wallet_file: PathBuf,
 
+    /// number of rotated wallet file backups to keep, eg .wallet.dat.1, .wallet.dat.2
+    #[structopt(long, default_value = "3")]
+    wallet_backups: usize,
+
+    /// where the wallet is kept: file, sled (a database at <wallet-file>.db) or memory
+    #[structopt(long, default_value = "file")]
+    wallet_store: WalletStoreKind,
+
+    /// number of members in each input's ring: the Dbc being spent plus decoys
+    /// obtained from the spentbook.  1 means no decoys.
+    #[structopt(long, default_value = "8")]
+    ring_size: usize,
+
+    /// do not connect to the spentbook.  Reissues are only built, then
+    /// exported for an online wallet to broadcast.  See the tx command.
+    #[structopt(long)]
+    offline: bool,
+
     #[structopt(flatten)]
     wallet_qp2p_opts: Config,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 enum Ownership {
     Mine,
     NotMine,
     Bearer,
+    /// owned by a public key we watch, but hold no secret key for.
+    WatchOnly,
+}
+impl std::str::FromStr for Ownership {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mine" => Ok(Self::Mine),
+            "notmine" => Ok(Self::NotMine),
+            "bearer" => Ok(Self::Bearer),
+            "watch" => Ok(Self::WatchOnly),
+            _ => Err(miette!(
+                "Unknown ownership '{}'.  Expected mine, notmine, bearer or watch",
+                s
+            )),
+        }
+    }
 }
 impl fmt::Display for Ownership {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -63,6 +104,7 @@ impl fmt::Display for Ownership {
             Self::Mine => "mine",
             Self::NotMine => "not mine",
             Self::Bearer => "bearer",
+            Self::WatchOnly => "watch-only",
         };
         write!(f, "{}", label)
     }
@@ -70,6 +112,25 @@ impl fmt::Display for Ownership {
 
 type KeyRing = BTreeMap<PublicKey, SerdeSecret<SecretKey>>;
 
+/// Account that holds every key not assigned to a named account.
+const DEFAULT_ACCOUNT: &str = "default";
+
+/// Maximum number of inputs per consolidate transaction, unless
+/// overridden with `batch=<n>`.
+const CONSOLIDATE_BATCH_SIZE: usize = 50;
+
+/// Number of decoys exported by `tx decoys`, unless overridden with
+/// `count=<n>`.
+const OFFLINE_DECOYS: usize = 100;
+
+/// Maximum number of outputs of a reissue_autogen or reissue_multi
+/// transaction, not counting change.
+const MAX_OUTPUTS: usize = 100;
+
+/// How many keys past `Wallet::next_key_index` to search when a Dbc's
+/// owner is not in the keyring, eg after restoring from a recovery phrase.
+const KEY_GAP_LIMIT: u64 = 100;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct DbcInfo {
     dbc: Dbc,
@@ -80,101 +141,2108 @@ struct DbcInfo {
     #[serde(with = "chrono::serde::ts_seconds_option")]
     spent: Option<chrono::DateTime<chrono::Utc>>,
     notes: String,
+
+    /// amount secrets, decrypted once when we hold the Dbc's key, or else
+    /// supplied with a watch-only Dbc.
+    #[serde(default)]
+    amount_secrets: Option<AmountSecrets>,
+
+    #[serde(default)]
+    tags: BTreeSet<String>,
 }
 
 impl DbcInfo {
-    fn ownership(&self, keyset: &KeyRing) -> Ownership {
+    fn tags_list(&self) -> String {
+        self.tags.iter().cloned().collect::<Vec<_>>().join(",")
+    }
+
+    fn ownership(&self, wallet: &Wallet) -> Ownership {
+        let owner = self.dbc.owner_base().public_key();
         if self.dbc.is_bearer() {
             return Ownership::Bearer;
-        } else if keyset.contains_key(&self.dbc.owner_base().public_key()) {
+        } else if wallet.keys.contains_key(&owner) {
             return Ownership::Mine;
+        } else if wallet.watch_keys.contains(&owner) {
+            return Ownership::WatchOnly;
         }
         Ownership::NotMine
     }
-}
+}
+
+/// An unspent Dbc in our wallet, with the secrets needed to value and spend it.
+struct UnspentDbc<'a> {
+    dinfo: &'a DbcInfo,
+
+    /// None for watch-only Dbcs, which we can not spend.
+    secret_key: Option<SecretKey>,
+    amount_secrets: AmountSecrets,
+    id: String,
+    ownership: Ownership,
+}
+
+/// An input to a reissue, with the secrets needed to spend it.
+#[derive(Clone)]
+struct ReissueInput {
+    dbc: Dbc,
+    secret_key: SecretKey,
+    amount: Amount,
+}
+
+/// A reissue whose transaction has been built but whose outputs have not,
+/// as its inputs are not yet all logged as spent with the spentbook.
+///
+/// It is saved before anything is broadcast, so that a reissue interrupted
+/// by a crash or network error can be resumed or rolled back.
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingReissue {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    created: chrono::DateTime<chrono::Utc>,
+
+    transaction: RingCtTransaction,
+    revealed_commitments: Vec<RevealedCommitment>,
+    output_owner_map: OutputOwnerMap,
+
+    /// spent proof shares received so far, for each input logged as spent.
+    shares: BTreeMap<KeyImage, Vec<SpentProofShare>>,
+
+    /// the spentbook nodes each share in shares came from, so that on
+    /// resume an input is not sent again to a node that already logged it.
+    #[serde(default)]
+    answered: BTreeMap<KeyImage, BTreeSet<XorName>>,
+
+    /// set once any spentbook node may have logged an input as spent: it
+    /// returned a share, or the transaction was exported for broadcast.
+    /// The reissue can then no longer be rolled back.
+    #[serde(default)]
+    logged: bool,
+
+    /// hashes of the input Dbcs, which are reserved until the reissue
+    /// completes or is rolled back.
+    inputs: Vec<[u8; 32]>,
+
+    /// derivation index of the change output, if any.
+    change_index: Option<[u8; 32]>,
+    notes: String,
+}
+
+impl PendingReissue {
+    fn dbc_builder(&self) -> DbcBuilder {
+        DbcBuilder::new(
+            self.transaction.clone(),
+            self.revealed_commitments.clone(),
+            self.output_owner_map.clone(),
+        )
+    }
+}
+
+/// A reissue transaction built by an offline wallet, exported for an online
+/// wallet to log as spent with the spentbook.
+#[derive(Serialize, Deserialize)]
+struct OfflineTx {
+    transaction: RingCtTransaction,
+    key_images: Vec<KeyImage>,
+}
+
+/// Decoys exported by an online wallet, along with the spentbook key that
+/// the offline wallet pins, to check spent proofs against later.
+#[derive(Serialize, Deserialize)]
+struct OfflineDecoys {
+    spentbook_pks: PublicKeySet,
+    decoys: Vec<DecoyInput>,
+}
+
+/// The spentbook's replies to an OfflineTx, exported by the online wallet
+/// for the offline wallet to build the output Dbcs with.
+#[derive(Serialize, Deserialize)]
+struct OfflineProofs {
+    spentbook_pks: PublicKeySet,
+    shares: BTreeMap<KeyImage, Vec<SpentProofShare>>,
+}
+
+/// An output of a reissue, other than change.
+struct ReissueOutput {
+    owner: Owner,
+    amount: Amount,
+}
+
+/// Criteria for filtering Dbc listings, parsed from `key=value` command args:
+///
+///   from=YYYY-MM-DD  to=YYYY-MM-DD  owner=mine|notmine|bearer|watch  account=<name>
+///   tag=<tag>  text=<text>  min=<amount>  max=<amount>
+///
+/// Dates and amounts are inclusive.  text matches notes, ignoring case.
+/// owner=notmine is only accepted where spent Dbcs are listed.
+#[derive(Default)]
+struct DbcFilter {
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    ownership: Option<Ownership>,
+    account: Option<String>,
+    tag: Option<String>,
+    text: Option<String>,
+    min: Option<Amount>,
+    max: Option<Amount>,
+}
+
+impl DbcFilter {
+    fn parse<'a>(args: impl Iterator<Item = &'a str>) -> Result<Self> {
+        let mut filter = Self::default();
+        let allowed = [
+            "from", "to", "owner", "account", "tag", "text", "min", "max",
+        ];
+        for (key, value) in parse_kv_args(args, &allowed)? {
+            match key {
+                "from" => filter.from = Some(parse_date(value)?),
+                "to" => filter.to = Some(parse_date(value)? + chrono::Duration::days(1)),
+                "owner" => filter.ownership = Some(value.parse()?),
+                "tag" => filter.tag = Some(value.to_string()),
+                "text" => filter.text = Some(value.to_lowercase()),
+                "min" => filter.min = Some(value.parse().into_diagnostic()?),
+                "max" => filter.max = Some(value.parse().into_diagnostic()?),
+                _ => filter.account = Some(value.to_string()),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Parses a filter for commands that list unspent Dbcs.  Those hold
+    /// only Dbcs we can spend or watch, which owner=notmine never matches.
+    fn parse_unspent<'a>(args: impl Iterator<Item = &'a str>) -> Result<Self> {
+        let filter = Self::parse(args)?;
+        if matches!(filter.ownership, Some(Ownership::NotMine)) {
+            return Err(miette!(
+                "owner=notmine matches no unspent Dbcs.  Use it with search or spent"
+            ));
+        }
+        Ok(filter)
+    }
+
+    /// Returns true if dinfo, dated at date, passes the filter.
+    fn matches(
+        &self,
+        wallet: &Wallet,
+        dinfo: &DbcInfo,
+        date: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        self.from.map_or(true, |from| date >= from)
+            && self.to.map_or(true, |to| date < to)
+            && self
+                .ownership
+                .as_ref()
+                .map_or(true, |o| *o == dinfo.ownership(wallet))
+            && self.account.as_ref().map_or(true, |a| {
+                a == wallet.account_of(&dinfo.dbc.owner_base().public_key())
+            })
+            && self.tag.as_ref().map_or(true, |t| dinfo.tags.contains(t))
+            && self
+                .text
+                .as_ref()
+                .map_or(true, |t| dinfo.notes.to_lowercase().contains(t))
+            && self.matches_amount(wallet.dbc_amount(dinfo))
+    }
+
+    /// Returns true if amount is within min and max.  An unknown amount
+    /// only passes if neither is set.
+    fn matches_amount(&self, amount: Option<Amount>) -> bool {
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+        amount.map_or(false, |amount| {
+            self.min.map_or(true, |min| amount >= min) && self.max.map_or(true, |max| amount <= max)
+        })
+    }
+}
+
+/// Strategies for choosing which unspent Dbcs to spend as the inputs of a reissue.
+mod coin_selection {
+    use miette::{miette, Result};
+    use serde::{Deserialize, Serialize};
+    use sn_dbc::Amount;
+    use std::fmt;
+
+    /// Maximum number of steps the exact match search may take before
+    /// giving up and falling back to largest-first.
+    const EXACT_MATCH_MAX_STEPS: usize = 100_000;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub enum Strategy {
+        /// spend the largest Dbcs first.
+        LargestFirst,
+        /// spend the smallest Dbcs first, to consolidate dust.
+        SmallestFirst,
+        /// spend Dbcs that add up to exactly the amount, so there is no
+        /// change, if any do.  Otherwise largest-first.
+        ExactMatch,
+        /// spend bearer Dbcs before owned ones, as anyone holding a copy can spend them.
+        BearerFirst,
+        /// spend as few Dbcs as possible, with as little change as possible.
+        MinimizeInputs,
+    }
+
+    impl Default for Strategy {
+        fn default() -> Self {
+            Self::LargestFirst
+        }
+    }
+
+    impl std::str::FromStr for Strategy {
+        type Err = miette::Report;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s {
+                "largest" => Ok(Self::LargestFirst),
+                "smallest" => Ok(Self::SmallestFirst),
+                "exact" => Ok(Self::ExactMatch),
+                "bearer" => Ok(Self::BearerFirst),
+                "minimize" => Ok(Self::MinimizeInputs),
+                _ => Err(miette!(
+                    "Unknown strategy '{}'.  Expected largest, smallest, exact, bearer or minimize",
+                    s
+                )),
+            }
+        }
+    }
+
+    impl fmt::Display for Strategy {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let label = match self {
+                Self::LargestFirst => "largest",
+                Self::SmallestFirst => "smallest",
+                Self::ExactMatch => "exact",
+                Self::BearerFirst => "bearer",
+                Self::MinimizeInputs => "minimize",
+            };
+            write!(f, "{}", label)
+        }
+    }
+
+    /// A Dbc that may be spent.
+    pub struct Candidate {
+        pub amount: Amount,
+        pub bearer: bool,
+    }
+
+    /// Returns the indexes of the candidates to spend for target, in the
+    /// order they should be added to the transaction.
+    pub fn select(
+        strategy: Strategy,
+        candidates: &[Candidate],
+        target: Amount,
+    ) -> Result<Vec<usize>> {
+        if target == 0 {
+            return Err(miette!("Amount to spend must be greater than zero"));
+        }
+        let total = candidates
+            .iter()
+            .fold(0, |total: Amount, c| total.saturating_add(c.amount));
+        if total < target {
+            return Err(miette!(
+                "Insufficient funds.  Need {} but only {} available",
+                target,
+                total
+            ));
+        }
+
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        match strategy {
+            Strategy::LargestFirst => {
+                order.sort_by_key(|i| std::cmp::Reverse(candidates[*i].amount));
+            }
+            Strategy::SmallestFirst => {
+                order.sort_by_key(|i| candidates[*i].amount);
+            }
+            Strategy::BearerFirst => {
+                order.sort_by_key(|i| {
+                    (
+                        !candidates[*i].bearer,
+                        std::cmp::Reverse(candidates[*i].amount),
+                    )
+                });
+            }
+            Strategy::ExactMatch => {
+                if let Some(exact) = exact_match(candidates, target) {
+                    return Ok(exact);
+                }
+                return select(Strategy::LargestFirst, candidates, target);
+            }
+            Strategy::MinimizeInputs => {
+                // a single Dbc is best, and the smallest that suffices
+                // leaves the least change.
+                let single = order
+                    .iter()
+                    .filter(|i| candidates[**i].amount >= target)
+                    .min_by_key(|i| candidates[**i].amount);
+                if let Some(i) = single {
+                    return Ok(vec![*i]);
+                }
+                return select(Strategy::LargestFirst, candidates, target);
+            }
+        }
+        Ok(take_until(candidates, order, target))
+    }
+
+    /// Takes candidates in order until their sum reaches target.
+    fn take_until(candidates: &[Candidate], order: Vec<usize>, target: Amount) -> Vec<usize> {
+        let mut sum: Amount = 0;
+        order
+            .into_iter()
+            .take_while(|i| {
+                let needed = sum < target;
+                sum = sum.saturating_add(candidates[*i].amount);
+                needed
+            })
+            .collect()
+    }
+
+    /// Searches for a set of candidates that sum to exactly target, largest
+    /// first.  Gives up after EXACT_MATCH_MAX_STEPS.
+    fn exact_match(candidates: &[Candidate], target: Amount) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(candidates[*i].amount));
+
+        // remaining[n] is the sum of the candidates from order[n] on, so a
+        // branch that can no longer reach target is pruned.
+        let mut remaining: Vec<Amount> = vec![0; order.len() + 1];
+        for n in (0..order.len()).rev() {
+            remaining[n] = remaining[n + 1].saturating_add(candidates[order[n]].amount);
+        }
+
+        // depth first search, with an explicit stack so that thousands of
+        // candidates can not overflow the call stack.  Each entry is the
+        // next candidate to consider, the target still to reach, the length
+        // of chosen to backtrack to and the candidate to then choose.
+        let mut stack: Vec<(usize, Amount, usize, Option<usize>)> = vec![(0, target, 0, None)];
+        let mut chosen = Vec::new();
+        let mut steps = 0;
+        while let Some((n, target, len, pick)) = stack.pop() {
+            chosen.truncate(len);
+            chosen.extend(pick);
+            if target == 0 {
+                return Some(chosen);
+            }
+            steps += 1;
+            if steps > EXACT_MATCH_MAX_STEPS {
+                return None;
+            }
+            if n == order.len() || remaining[n] < target {
+                continue;
+            }
+
+            // pushed last so that including the candidate is tried first.
+            stack.push((n + 1, target, chosen.len(), None));
+            let amount = candidates[order[n]].amount;
+            if amount <= target {
+                stack.push((n + 1, target - amount, chosen.len(), Some(order[n])));
+            }
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn candidates(amounts: &[Amount]) -> Vec<Candidate> {
+            amounts
+                .iter()
+                .map(|amount| Candidate {
+                    amount: *amount,
+                    bearer: false,
+                })
+                .collect()
+        }
+
+        #[test]
+        fn select_cases() {
+            use Strategy::*;
+            let cases: &[(Strategy, &[Amount], Amount, Option<Vec<usize>>)] = &[
+                (LargestFirst, &[], 1, None),
+                (LargestFirst, &[5, 5], 0, None),
+                (LargestFirst, &[5, 5], 11, None),
+                (LargestFirst, &[1, 10, 5], 6, Some(vec![1])),
+                (LargestFirst, &[1, 10, 5], 12, Some(vec![1, 2])),
+                (LargestFirst, &[5, 5, 5], 15, Some(vec![0, 1, 2])),
+                (SmallestFirst, &[1, 10, 5], 6, Some(vec![0, 2])),
+                (SmallestFirst, &[4, 4, 4], 5, Some(vec![0, 1])),
+                (ExactMatch, &[10, 7, 3, 2], 5, Some(vec![2, 3])),
+                (ExactMatch, &[10, 7, 3, 2], 12, Some(vec![0, 3])),
+                (ExactMatch, &[4, 4, 4], 8, Some(vec![0, 1])),
+                (ExactMatch, &[10, 7], 8, Some(vec![0])),
+                (ExactMatch, &[10, 7], 0, None),
+                (MinimizeInputs, &[1, 10, 5, 7], 6, Some(vec![3])),
+                (MinimizeInputs, &[1, 10, 5], 12, Some(vec![1, 2])),
+                (MinimizeInputs, &[5, 5], 5, Some(vec![0])),
+                (MinimizeInputs, &[5, 5], 0, None),
+            ];
+            for (strategy, amounts, target, expected) in cases.iter() {
+                assert_eq!(
+                    select(*strategy, &candidates(amounts), *target).ok(),
+                    *expected,
+                    "strategy: {}, amounts: {:?}, target: {}",
+                    strategy,
+                    amounts,
+                    target
+                );
+            }
+        }
+
+        #[test]
+        fn select_bearer_first() {
+            let mut cands = candidates(&[10, 2, 3]);
+            cands[1].bearer = true;
+            cands[2].bearer = true;
+            assert_eq!(
+                select(Strategy::BearerFirst, &cands, 4).ok(),
+                Some(vec![2, 1])
+            );
+            assert_eq!(
+                select(Strategy::BearerFirst, &cands, 6).ok(),
+                Some(vec![2, 1, 0])
+            );
+        }
+
+        #[test]
+        fn exact_match_many_candidates() {
+            // no exact match exists, so the search runs until its step limit.
+            let cands = candidates(&vec![2; 10_000]);
+            assert_eq!(exact_match(&cands, 3), None);
+            assert_eq!(
+                select(Strategy::ExactMatch, &cands, 3)
+                    .ok()
+                    .map(|s| s.len()),
+                Some(2)
+            );
+            assert_eq!(exact_match(&cands, 20_000).map(|s| s.len()), Some(10_000));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum TxKind {
+    Genesis,
+    Reissue,
+}
+impl fmt::Display for TxKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Self::Genesis => "genesis",
+            Self::Reissue => "reissue",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// An output Dbc of a transaction in the wallet's history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TxOutput {
+    dbc_hash: [u8; 32],
+    amount: Amount,
+
+    /// owner of the output, or None if it is a bearer Dbc.
+    recipient: Option<PublicKey>,
+
+    /// true if this is change returned to our own wallet.
+    change: bool,
+}
+
+impl TxOutput {
+    fn new(dbc: &Dbc, amount: Amount, change: bool) -> Self {
+        Self {
+            dbc_hash: dbc.hash(),
+            amount,
+            recipient: if dbc.is_bearer() {
+                None
+            } else {
+                Some(dbc.owner_base().public_key())
+            },
+            change,
+        }
+    }
+}
+
+/// An address book entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Contact {
+    public_key: PublicKey,
+    notes: String,
+}
+
+/// A transaction issued by this wallet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TxRecord {
+    kind: TxKind,
+
+    #[serde(with = "chrono::serde::ts_seconds")]
+    time: chrono::DateTime<chrono::Utc>,
+
+    /// hashes of the Dbcs spent as inputs
+    inputs: Vec<[u8; 32]>,
+    outputs: Vec<TxOutput>,
+    notes: String,
+}
+
+impl TxRecord {
+    fn new(kind: TxKind, inputs: Vec<[u8; 32]>, notes: String) -> Self {
+        Self {
+            kind,
+            time: chrono::Utc::now(),
+            inputs,
+            outputs: Default::default(),
+            notes,
+        }
+    }
+
+    /// sum of the outputs, excluding change
+    fn amount_sent(&self) -> Amount {
+        self.outputs
+            .iter()
+            .filter(|o| !o.change)
+            .map(|o| o.amount)
+            .sum()
+    }
+}
+
+// axes:
+//  spent/unspent
+//  received/sent        (sent to self would be both sent+received)
+//  bearer/owned
+//  reissued_by_me/reissued_by_other
+
+//Unspent:
+// 1. owned dbcs for which owner matches one of my keys.            (received)
+// 2. owned dbcs for which owner does not match one of my keys.     (sent)
+// 3. bearer dbcs                                                   (received)
+
+//Spent:
+// 1. owned dbcs for which owner matches one of my keys.
+// 2. owned dbcs for which owner does not match one of my keys.
+// 3. bearer dbcs
+
+/// Every wallet file begins with this magic, followed by the
+/// file format version as a little-endian u16.
+const WALLET_MAGIC: &[u8; 8] = b"SNDBCWLT";
+
+/// Current wallet file format.
+///
+/// Files written before the header was introduced are treated as version 0
+/// and migrated on load.  Version 1 stores the Wallet as MessagePack with
+/// named fields, so adding a field with `#[serde(default)]` does not
+/// require a new version.
+const WALLET_FORMAT_VERSION: u16 = 1;
+
+/// Wallet layout of format version 0 files, kept for migration.
+#[derive(Deserialize)]
+struct WalletV0 {
+    dbcs: HashMap<[u8; 32], DbcInfoV0>,
+    keys: KeyRing,
+}
+
+/// DbcInfo layout of format version 0 files, kept for migration.
+#[derive(Deserialize)]
+struct DbcInfoV0 {
+    dbc: Dbc,
+
+    #[serde(with = "chrono::serde::ts_seconds")]
+    received: chrono::DateTime<chrono::Utc>,
+
+    #[serde(with = "chrono::serde::ts_seconds_option")]
+    spent: Option<chrono::DateTime<chrono::Utc>>,
+    notes: String,
+}
+
+impl From<WalletV0> for Wallet {
+    fn from(v0: WalletV0) -> Self {
+        let dbcs = v0
+            .dbcs
+            .into_iter()
+            .map(|(hash, d)| {
+                let dinfo = DbcInfo {
+                    dbc: d.dbc,
+                    received: d.received,
+                    spent: d.spent,
+                    notes: d.notes,
+                    amount_secrets: None,
+                    tags: Default::default(),
+                };
+                (hash, dinfo)
+            })
+            .collect();
+        Self {
+            dbcs,
+            keys: v0.keys,
+            ..Default::default()
+        }
+    }
+}
+
+/// On-disk form of the wallet, following the header.  The serialized Wallet
+/// is encrypted with a key derived from the user's passphrase and `salt`.
+#[derive(Serialize, Deserialize)]
+struct EncryptedWallet {
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+/// Symmetric key that protects the wallet file, derived from a passphrase.
+struct WalletCipher {
+    salt: [u8; 16],
+    key: [u8; 32],
+}
+
+impl WalletCipher {
+    /// Creates a cipher for a new passphrase, with a fresh random salt.
+    fn new(passphrase: &str) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        rng::thread_rng().fill_bytes(&mut salt);
+        Self::derive(passphrase, salt)
+    }
+
+    /// Derives the key for passphrase using an existing salt.
+    fn derive(passphrase: &str, salt: [u8; 16]) -> Result<Self> {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| miette!("Unable to derive wallet key: {}", e))?;
+        Ok(Self { salt, key })
+    }
+
+    /// Returns true if passphrase derives this cipher's key.  The keys are
+    /// compared in constant time.
+    fn matches(&self, passphrase: &str) -> Result<bool> {
+        use subtle::ConstantTimeEq;
+        let derived = Self::derive(passphrase, self.salt)?;
+        Ok(bool::from(derived.key.ct_eq(&self.key)))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedWallet> {
+        let mut nonce = [0u8; 12];
+        rng::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| miette!("Unable to encrypt wallet"))?;
+        Ok(EncryptedWallet {
+            salt: self.salt,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn decrypt(&self, encrypted: &EncryptedWallet) -> Result<Vec<u8>> {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .decrypt(
+                Nonce::from_slice(&encrypted.nonce),
+                encrypted.ciphertext.as_ref(),
+            )
+            .map_err(|_| miette!("Unable to decrypt wallet.  Wrong passphrase?"))
+    }
+
+    /// Encrypts a single record, returning the nonce followed by the ciphertext.
+    /// Unlike encrypt(), the salt is not included, so it must be kept elsewhere.
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let encrypted = self.encrypt(plaintext)?;
+        let mut sealed = encrypted.nonce.to_vec();
+        sealed.extend_from_slice(&encrypted.ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts a record created by seal().
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < 12 {
+            return Err(miette!("Encrypted record is truncated"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let mut encrypted = EncryptedWallet {
+            salt: self.salt,
+            nonce: [0u8; 12],
+            ciphertext: ciphertext.to_vec(),
+        };
+        encrypted.nonce.copy_from_slice(nonce);
+        self.decrypt(&encrypted)
+    }
+}
+
+/// A part of the wallet that has changed since it was last written to
+/// its store.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum WalletChange {
+    /// the Dbc with this hash was added or updated.
+    Dbc([u8; 32]),
+    /// this key was added to the keyring.
+    Key(PublicKey),
+    /// any field other than dbcs and keys.
+    Meta,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Wallet {
+    dbcs: HashMap<[u8; 32], DbcInfo>,
+    keys: KeyRing,
+
+    /// seed of the recovery phrase, from which keys are derived by index.
+    #[serde(default)]
+    seed: Option<Vec<u8>>,
+
+    /// index of the next key to derive from seed.
+    #[serde(default)]
+    next_key_index: u64,
+
+    /// transactions issued by this wallet, oldest first.
+    #[serde(default)]
+    history: Vec<TxRecord>,
+
+    /// named accounts and the keys they own.  Keys that are not in any
+    /// named account belong to DEFAULT_ACCOUNT.
+    #[serde(default)]
+    accounts: BTreeMap<String, BTreeSet<PublicKey>>,
+
+    /// public keys whose Dbcs we track without holding the secret key.
+    #[serde(default)]
+    watch_keys: BTreeSet<PublicKey>,
+
+    /// address book of recipients, by alias.
+    #[serde(default)]
+    contacts: BTreeMap<String, Contact>,
+
+    /// coin selection strategy for reissues that do not specify one.
+    #[serde(default)]
+    strategy: coin_selection::Strategy,
+
+    /// a reissue that has not yet completed.
+    #[serde(default)]
+    pending: Option<PendingReissue>,
+
+    /// the spentbook key confirmed by the user of an offline wallet.  Spent
+    /// proofs brought from an online wallet must be signed with it.
+    #[serde(default)]
+    pinned_spentbook_pks: Option<PublicKeySet>,
+
+    /// changes not yet written to the store.
+    #[serde(skip)]
+    changes: BTreeSet<WalletChange>,
+
+    /// hashes of unspent Dbcs.  This and the other indexes are rebuilt by
+    /// reindex() when the wallet is loaded.
+    #[serde(skip)]
+    unspent_index: BTreeSet<[u8; 32]>,
+
+    /// hashes of Dbcs, by owner base public key.
+    #[serde(skip)]
+    owner_index: BTreeMap<PublicKey, BTreeSet<[u8; 32]>>,
+
+    /// hashes of Dbcs we are able to spend, by key image.
+    #[serde(skip)]
+    key_image_index: BTreeMap<KeyImage, [u8; 32]>,
+
+    /// the named account of each key assigned to one.
+    #[serde(skip)]
+    account_index: BTreeMap<PublicKey, String>,
+}
+
+impl Wallet {
+    fn unspent(&self) -> BTreeMap<&[u8; 32], &DbcInfo> {
+        self.unspent_index
+            .iter()
+            .filter_map(|dbc_hash| self.dbcs.get_key_value(dbc_hash))
+            .collect()
+    }
+
+    /// Returns the hash of the Dbc that key_image spends, if it is ours.
+    fn dbc_hash_by_key_image(&self, key_image: &KeyImage) -> Option<[u8; 32]> {
+        self.key_image_index.get(key_image).copied()
+    }
+
+    /// Rebuilds the indexes from dbcs and accounts.
+    fn reindex(&mut self) {
+        self.unspent_index.clear();
+        self.owner_index.clear();
+        self.key_image_index.clear();
+
+        self.account_index = self
+            .accounts
+            .iter()
+            .flat_map(|(name, keys)| keys.iter().map(move |pk| (*pk, name.clone())))
+            .collect();
+
+        let dbc_hashes: Vec<[u8; 32]> = self.dbcs.keys().copied().collect();
+        for dbc_hash in dbc_hashes.iter() {
+            self.index_dbc(dbc_hash);
+        }
+    }
+
+    /// Adds the Dbc with dbc_hash to the indexes, or updates its entries.
+    /// Its amount secrets are cached too, if not already.
+    fn index_dbc(&mut self, dbc_hash: &[u8; 32]) {
+        self.cache_amount_secrets(dbc_hash);
+
+        let dinfo = match self.dbcs.get(dbc_hash) {
+            Some(dinfo) => dinfo,
+            None => return,
+        };
+        if dinfo.spent.is_none() {
+            self.unspent_index.insert(*dbc_hash);
+        } else {
+            self.unspent_index.remove(dbc_hash);
+        }
+        self.owner_index
+            .entry(dinfo.dbc.owner_base().public_key())
+            .or_default()
+            .insert(*dbc_hash);
+        if let Some(key_image) = self.key_image_of(dinfo) {
+            self.key_image_index.insert(key_image, *dbc_hash);
+        }
+    }
+
+    /// Decrypts the amount secrets of the Dbc with dbc_hash and stores them
+    /// in its DbcInfo, so that they need not be decrypted again.  Does
+    /// nothing if they are already cached or we do not hold its key.
+    fn cache_amount_secrets(&mut self, dbc_hash: &[u8; 32]) {
+        let amount_secrets = match self.dbcs.get(dbc_hash) {
+            Some(dinfo) if dinfo.amount_secrets.is_none() => self
+                .secret_key_of(dinfo)
+                .and_then(|sk| dinfo.dbc.amount_secrets(&sk).ok()),
+            _ => None,
+        };
+        if let Some(amount_secrets) = amount_secrets {
+            self.set_amount_secrets(dbc_hash, amount_secrets);
+        }
+    }
+
+    /// Returns the base secret key of dinfo, if we hold it.
+    fn secret_key_of(&self, dinfo: &DbcInfo) -> Option<SecretKey> {
+        self.dbc_secret_key(&dinfo.dbc)
+    }
+
+    /// Returns the base secret key of dbc, which need not be in the wallet,
+    /// if we hold it.
+    fn dbc_secret_key(&self, dbc: &Dbc) -> Option<SecretKey> {
+        if dbc.is_bearer() {
+            dbc.owner_base().secret_key().ok()
+        } else {
+            self.keys
+                .get(&dbc.owner_base().public_key())
+                .map(|sk| sk.inner().clone())
+        }
+    }
+
+    /// Returns the key image of dinfo, if we hold the key to spend it.
+    fn key_image_of(&self, dinfo: &DbcInfo) -> Option<KeyImage> {
+        dinfo.dbc.key_image(&self.secret_key_of(dinfo)?).ok()
+    }
+
+    fn addkey(&mut self, sk: SecretKey) {
+        let public_key = sk.public_key();
+        self.changes.insert(WalletChange::Key(public_key));
+        self.keys.insert(public_key, SerdeSecret(sk));
+
+        // Dbcs we already have for this key, eg watch-only, can now be spent.
+        if let Some(dbc_hashes) = self.owner_index.get(&public_key).cloned() {
+            for dbc_hash in dbc_hashes.iter() {
+                self.index_dbc(dbc_hash);
+            }
+        }
+    }
+
+    /// Returns the changes made since the last call, clearing them.
+    fn take_changes(&mut self) -> BTreeSet<WalletChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Marks every part of the wallet as changed.
+    fn touch_all(&mut self) {
+        let dbcs = self.dbcs.keys().map(|h| WalletChange::Dbc(*h));
+        let keys = self.keys.keys().map(|pk| WalletChange::Key(*pk));
+        self.changes = dbcs.chain(keys).collect();
+        self.changes.insert(WalletChange::Meta);
+    }
+
+    /// Serializes every field except dbcs and keys, which a store may
+    /// keep as separate records.
+    fn meta_bytes(&mut self) -> Result<Vec<u8>> {
+        let dbcs = std::mem::take(&mut self.dbcs);
+        let keys = std::mem::take(&mut self.keys);
+        let bytes = rmp_serde::to_vec_named(&self).into_diagnostic();
+        self.dbcs = dbcs;
+        self.keys = keys;
+        bytes
+    }
+
+    /// Derives the next key from the wallet seed and adds it to the keyring.
+    fn new_key(&mut self) -> Result<SecretKey> {
+        let seed = self
+            .seed
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet has no seed"))?;
+        let secret_key = derive_secret_key(seed, self.next_key_index);
+        self.next_key_index += 1;
+        self.changes.insert(WalletChange::Meta);
+        self.addkey(secret_key.clone());
+        Ok(secret_key)
+    }
+
+    /// Derives the next key from the wallet seed and assigns it to account.
+    fn new_account_key(&mut self, account: &str) -> Result<SecretKey> {
+        self.check_account(account)?;
+        let secret_key = self.new_key()?;
+        self.assign_key(secret_key.public_key(), account)?;
+        Ok(secret_key)
+    }
+
+    /// Returns the name of the account that owns public_key.
+    fn account_of(&self, public_key: &PublicKey) -> &str {
+        self.account_index
+            .get(public_key)
+            .map(|name| name.as_str())
+            .unwrap_or(DEFAULT_ACCOUNT)
+    }
+
+    fn check_account(&self, account: &str) -> Result<()> {
+        if account == DEFAULT_ACCOUNT || self.accounts.contains_key(account) {
+            Ok(())
+        } else {
+            Err(miette!("Unknown account '{}'", account))
+        }
+    }
+
+    fn add_account(&mut self, account: &str) -> Result<()> {
+        if account == DEFAULT_ACCOUNT || self.accounts.contains_key(account) {
+            return Err(miette!("Account '{}' already exists", account));
+        }
+        self.accounts
+            .insert(account.to_string(), Default::default());
+        self.changes.insert(WalletChange::Meta);
+        Ok(())
+    }
+
+    /// Removes a named account.  Its keys move to the default account.
+    fn remove_account(&mut self, account: &str) -> Result<()> {
+        if account == DEFAULT_ACCOUNT {
+            return Err(miette!("The default account can not be removed"));
+        }
+        let keys = self
+            .accounts
+            .remove(account)
+            .ok_or_else(|| miette!("Unknown account '{}'", account))?;
+        for public_key in keys.iter() {
+            self.account_index.remove(public_key);
+        }
+        self.changes.insert(WalletChange::Meta);
+        Ok(())
+    }
+
+    /// Moves public_key from its current account to account.
+    fn assign_key(&mut self, public_key: PublicKey, account: &str) -> Result<()> {
+        self.check_account(account)?;
+        if !self.keys.contains_key(&public_key) {
+            return Err(miette!("Key not found in wallet"));
+        }
+        if let Some(previous) = self.account_index.remove(&public_key) {
+            if let Some(keys) = self.accounts.get_mut(&previous) {
+                keys.remove(&public_key);
+            }
+        }
+        if let Some(keys) = self.accounts.get_mut(account) {
+            keys.insert(public_key);
+            self.account_index.insert(public_key, account.to_string());
+        }
+        self.changes.insert(WalletChange::Meta);
+        Ok(())
+    }
+
+    /// Searches up to KEY_GAP_LIMIT derived keys beyond next_key_index
+    /// for the secret key of public_key.  If found, every key up to and
+    /// including it is added to the keyring.
+    ///
+    /// Which account a key was assigned to is not derived from the seed, so
+    /// recovered keys belong to DEFAULT_ACCOUNT until reassigned.
+    fn discover_key(&mut self, public_key: &PublicKey) -> Result<bool> {
+        let seed = match self.seed.as_ref() {
+            Some(seed) => seed,
+            None => return Ok(false),
+        };
+        let found = (self.next_key_index..self.next_key_index + KEY_GAP_LIMIT)
+            .find(|index| derive_secret_key(seed, *index).public_key() == *public_key);
+
+        if let Some(index) = found {
+            while self.next_key_index <= index {
+                self.new_key()?;
+            }
+        }
+        Ok(found.is_some())
+    }
+
+    fn spent(&self) -> BTreeMap<&[u8; 32], &DbcInfo> {
+        self.dbcs
+            .iter()
+            .filter(|(_, d)| d.spent.is_some())
+            .collect()
+    }
+
+    /// Returns the amount of a Dbc, if we can know it.  Dbcs owned by third
+    /// parties can only be decrypted by their owner, so for those we rely on
+    /// the amount recorded in our transaction history.
+    fn dbc_amount(&self, dinfo: &DbcInfo) -> Option<Amount> {
+        match dinfo.ownership(self) {
+            Ownership::Mine | Ownership::Bearer | Ownership::WatchOnly => {
+                dinfo.amount_secrets.as_ref().map(|s| s.amount())
+            }
+            Ownership::NotMine => {
+                let dbc_hash = dinfo.dbc.hash();
+                self.history
+                    .iter()
+                    .flat_map(|r| r.outputs.iter())
+                    .find(|o| o.dbc_hash == dbc_hash)
+                    .map(|o| o.amount)
+            }
+        }
+    }
+
+    fn record_tx(&mut self, record: TxRecord) {
+        self.history.push(record);
+        self.changes.insert(WalletChange::Meta);
+    }
+
+    /// Starts watching public_key.  Returns false if it was already watched.
+    fn watch(&mut self, public_key: PublicKey) -> bool {
+        self.changes.insert(WalletChange::Meta);
+        self.watch_keys.insert(public_key)
+    }
+
+    /// Stops watching public_key.  Returns false if it was not watched.
+    fn unwatch(&mut self, public_key: &PublicKey) -> bool {
+        self.changes.insert(WalletChange::Meta);
+        self.watch_keys.remove(public_key)
+    }
+
+    fn set_pending(&mut self, pending: Option<PendingReissue>) {
+        self.pending = pending;
+        self.changes.insert(WalletChange::Meta);
+    }
+
+    fn pin_spentbook_pks(&mut self, spentbook_pks: PublicKeySet) {
+        self.pinned_spentbook_pks = Some(spentbook_pks);
+        self.changes.insert(WalletChange::Meta);
+    }
+
+    fn set_pending_logged(&mut self) {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.logged = true;
+            self.changes.insert(WalletChange::Meta);
+        }
+    }
+
+    /// Records a share from one spentbook node for an input of the pending
+    /// reissue.
+    fn add_pending_share(&mut self, key_image: KeyImage, node: XorName, share: SpentProofShare) {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.shares.entry(key_image).or_default().push(share);
+            pending.answered.entry(key_image).or_default().insert(node);
+            pending.logged = true;
+            self.changes.insert(WalletChange::Meta);
+        }
+    }
+
+    fn add_pending_shares(&mut self, key_image: KeyImage, shares: Vec<SpentProofShare>) {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.shares.insert(key_image, shares);
+            self.changes.insert(WalletChange::Meta);
+        }
+    }
+
+    /// Returns true if dbc_hash is an input of the pending reissue.
+    fn is_reserved(&self, dbc_hash: &[u8; 32]) -> bool {
+        self.pending
+            .as_ref()
+            .map_or(false, |p| p.inputs.contains(dbc_hash))
+    }
+
+    fn set_strategy(&mut self, strategy: coin_selection::Strategy) {
+        self.strategy = strategy;
+        self.changes.insert(WalletChange::Meta);
+    }
+
+    fn add_contact(&mut self, alias: &str, public_key: PublicKey, notes: String) -> Result<()> {
+        if self.contacts.contains_key(alias) {
+            return Err(miette!("Contact '{}' already exists", alias));
+        }
+        self.contacts
+            .insert(alias.to_string(), Contact { public_key, notes });
+        self.changes.insert(WalletChange::Meta);
+        Ok(())
+    }
+
+    fn remove_contact(&mut self, alias: &str) -> Result<()> {
+        self.contacts
+            .remove(alias)
+            .ok_or_else(|| miette!("Unknown contact '{}'", alias))?;
+        self.changes.insert(WalletChange::Meta);
+        Ok(())
+    }
+
+    /// Returns the alias of the contact with public_key, if any.
+    fn contact_alias(&self, public_key: &PublicKey) -> Option<&str> {
+        self.contacts
+            .iter()
+            .find(|(_, c)| c.public_key == *public_key)
+            .map(|(alias, _)| alias.as_str())
+    }
+
+    /// Resolves a recipient given as a contact alias or a hex public key.
+    fn recipient_key(&self, recipient: &str) -> Result<PublicKey> {
+        match self.contacts.get(recipient) {
+            Some(contact) => Ok(contact.public_key),
+            None => public_key_from_hex(recipient).map_err(|_| {
+                miette!(
+                    "'{}' is neither a contact nor a valid public key",
+                    recipient
+                )
+            }),
+        }
+    }
+
+    /// Returns the hash of the one Dbc whose hex-encoded hash begins with prefix.
+    fn find_by_prefix(&self, prefix: &str) -> Result<[u8; 32]> {
+        let prefix = prefix.to_lowercase();
+        let mut found = self
+            .dbcs
+            .keys()
+            .filter(|dbc_hash| encode(dbc_hash).starts_with(&prefix));
+        match (found.next(), found.next()) {
+            (Some(dbc_hash), None) => Ok(*dbc_hash),
+            (None, _) => Err(miette!("No Dbc found with hash prefix '{}'", prefix)),
+            (Some(_), Some(_)) => Err(miette!(
+                "Hash prefix '{}' matches more than one Dbc.  Please give more digits",
+                prefix
+            )),
+        }
+    }
+
+    fn dbc_mut(&mut self, dbc_hash: &[u8; 32]) -> Result<&mut DbcInfo> {
+        self.changes.insert(WalletChange::Dbc(*dbc_hash));
+        self.dbcs
+            .get_mut(dbc_hash)
+            .ok_or_else(|| miette!("Dbc not found in wallet"))
+    }
+
+    fn set_notes(&mut self, dbc_hash: &[u8; 32], notes: String) -> Result<()> {
+        self.dbc_mut(dbc_hash)?.notes = notes;
+        Ok(())
+    }
+
+    /// Adds tag to a Dbc.  Returns false if it was already tagged.
+    fn add_tag(&mut self, dbc_hash: &[u8; 32], tag: &str) -> Result<bool> {
+        Ok(self.dbc_mut(dbc_hash)?.tags.insert(tag.to_string()))
+    }
+
+    /// Removes tag from a Dbc.  Returns false if it was not tagged.
+    fn remove_tag(&mut self, dbc_hash: &[u8; 32], tag: &str) -> Result<bool> {
+        Ok(self.dbc_mut(dbc_hash)?.tags.remove(tag))
+    }
+
+    fn set_amount_secrets(&mut self, dbc_hash: &[u8; 32], amount_secrets: AmountSecrets) {
+        if let Some(dinfo) = self.dbcs.get_mut(dbc_hash) {
+            dinfo.amount_secrets = Some(amount_secrets);
+            self.changes.insert(WalletChange::Dbc(*dbc_hash));
+        }
+    }
+
+    fn mark_spent(&mut self, dbc_hash: &[u8; 32]) {
+        if let Some(dbc) = self.dbcs.get_mut(dbc_hash) {
+            dbc.spent = Some(chrono::Utc::now());
+            self.unspent_index.remove(dbc_hash);
+            self.changes.insert(WalletChange::Dbc(*dbc_hash));
+        }
+    }
+
+    fn add_dbc(&mut self, dbc: Dbc, notes: Option<String>, sent: bool) -> Result<DbcInfo> {
+        if dbc.is_bearer() {
+            self.addkey(dbc.owner_base().secret_key().into_diagnostic()?);
+        } else if !self.keys.contains_key(&dbc.owner_base().public_key()) {
+            self.discover_key(&dbc.owner_base().public_key())?;
+        }
+
+        let dbc_hash = dbc.hash();
+        let dbc_info = DbcInfo {
+            dbc,
+            received: chrono::Utc::now(),
+            spent: if sent { Some(chrono::Utc::now()) } else { None },
+            notes: notes.unwrap_or_else(|| "".to_string()),
+            amount_secrets: None,
+            tags: Default::default(),
+        };
+        self.dbcs.insert(dbc_hash, dbc_info);
+        self.index_dbc(&dbc_hash);
+        self.changes.insert(WalletChange::Dbc(dbc_hash));
+
+        // cloned after indexing, so it includes the cached amount secrets.
+        Ok(self.dbcs[&dbc_hash].clone())
+    }
+
+    /// Merges other into this wallet, skipping keys and Dbcs we already have.
+    /// A Dbc that is marked spent in either wallet remains spent.
+    ///
+    /// Returns the number of keys and Dbcs added.
+    fn merge(&mut self, other: Wallet) -> (usize, usize) {
+        if self.seed.is_none() {
+            self.seed = other.seed.clone();
+            self.next_key_index = other.next_key_index;
+        } else if self.seed == other.seed {
+            self.next_key_index = self.next_key_index.max(other.next_key_index);
+        }
+
+        let mut keys_added = 0;
+        for (pk, sk) in other.keys.into_iter() {
+            if let btree_map::Entry::Vacant(e) = self.keys.entry(pk) {
+                e.insert(sk);
+                keys_added += 1;
+            }
+        }
+
+        let mut dbcs_added = 0;
+        for (dbc_hash, dinfo) in other.dbcs.into_iter() {
+            match self.dbcs.entry(dbc_hash) {
+                hash_map::Entry::Vacant(e) => {
+                    e.insert(dinfo);
+                    dbcs_added += 1;
+                }
+                hash_map::Entry::Occupied(mut e) => {
+                    let existing = e.get_mut();
+                    if existing.spent.is_none() {
+                        existing.spent = dinfo.spent;
+                    }
+                    if existing.amount_secrets.is_none() {
+                        existing.amount_secrets = dinfo.amount_secrets;
+                    }
+                    if existing.notes.is_empty() {
+                        existing.notes = dinfo.notes;
+                    }
+                    existing.tags.extend(dinfo.tags.into_iter());
+                }
+            }
+        }
+        self.watch_keys.extend(other.watch_keys.into_iter());
+        for (alias, contact) in other.contacts.into_iter() {
+            self.contacts.entry(alias).or_insert(contact);
+        }
+
+        for (account, keys) in other.accounts.into_iter() {
+            for pk in keys.into_iter() {
+                if self.account_of(&pk) == DEFAULT_ACCOUNT {
+                    self.accounts.entry(account.clone()).or_default().insert(pk);
+                    self.account_index.insert(pk, account.clone());
+                }
+            }
+        }
+
+        for record in other.history.into_iter() {
+            if !self.history.contains(&record) {
+                self.history.push(record);
+            }
+        }
+        self.history.sort_by_key(|r| r.time);
+        self.reindex();
+        self.touch_all();
+
+        (keys_added, dbcs_added)
+    }
+}
+
+/// Where and how a Wallet is persisted.
+///
+/// Mutating a Wallet records what changed.  commit() writes just those
+/// changes where the backend is able to.  save() leaves the store holding
+/// the whole wallet, which a backend with per-record writes may do by
+/// committing, unless the passphrase changed.
+trait WalletStore {
+    /// Returns true if the store already holds a wallet.
+    fn exists(&self) -> bool;
+
+    /// Loads and decrypts the wallet.  The cipher derived from passphrase
+    /// is kept for later writes.
+    fn load(&mut self, passphrase: &str) -> Result<Wallet>;
+
+    /// Writes the whole wallet, or as much of it as the store lacks.
+    fn save(&mut self, wallet: &mut Wallet) -> Result<()>;
+
+    /// Writes changes made since the wallet was last saved or committed.
+    /// By default the whole wallet is saved if anything changed.
+    fn commit(&mut self, wallet: &mut Wallet) -> Result<()> {
+        if wallet.changes.is_empty() {
+            return Ok(());
+        }
+        self.save(wallet)
+    }
+
+    /// The cipher that protects the wallet, once loaded or set.
+    fn cipher(&self) -> Option<&WalletCipher>;
+
+    /// Replaces the cipher.  It is used from the next save().
+    fn set_cipher(&mut self, cipher: WalletCipher);
+}
+
+/// Kind of WalletStore, selected with --wallet-store.
+#[derive(Debug, Clone, Copy)]
+enum WalletStoreKind {
+    File,
+    Sled,
+    Memory,
+}
+impl std::str::FromStr for WalletStoreKind {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "file" => Ok(Self::File),
+            "sled" => Ok(Self::Sled),
+            "memory" => Ok(Self::Memory),
+            _ => Err(miette!(
+                "Unknown wallet store '{}'.  Expected file, sled or memory",
+                s
+            )),
+        }
+    }
+}
+
+impl WalletStoreKind {
+    fn open(&self, path: &Path, backups: usize) -> Result<Box<dyn WalletStore>> {
+        Ok(match self {
+            Self::File => Box::new(FileStore::new(path, backups)),
+            Self::Sled => Box::new(SledStore::open(&suffixed_path(path, "db"))?),
+            Self::Memory => Box::new(MemoryStore::default()),
+        })
+    }
+}
+
+/// Serializes and encrypts the whole wallet, prefixed by the file header.
+fn encode_wallet(wallet: &Wallet, cipher: &WalletCipher) -> Result<Vec<u8>> {
+    let plaintext = rmp_serde::to_vec_named(wallet).into_diagnostic()?;
+
+    let mut bytes = WALLET_MAGIC.to_vec();
+    bytes.extend_from_slice(&WALLET_FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, &cipher.encrypt(&plaintext)?).into_diagnostic()?;
+    Ok(bytes)
+}
+
+/// Parses and decrypts bytes written by encode_wallet(), or by an
+/// older version of this program.
+fn decode_wallet(bytes: &[u8], passphrase: &str) -> Result<(Wallet, WalletCipher)> {
+    let (version, body) = match bytes.strip_prefix(WALLET_MAGIC) {
+        Some([v0, v1, body @ ..]) => (u16::from_le_bytes([*v0, *v1]), body),
+        Some(_) => return Err(miette!("Wallet file header is truncated")),
+        None => (0, bytes),
+    };
+
+    match version {
+        0 => migrate_v0(body, passphrase),
+        WALLET_FORMAT_VERSION => {
+            let encrypted: EncryptedWallet = bincode::deserialize(body).into_diagnostic()?;
+            let cipher = WalletCipher::derive(passphrase, encrypted.salt)?;
+            let plaintext = cipher.decrypt(&encrypted)?;
+            Ok((rmp_serde::from_slice(&plaintext).into_diagnostic()?, cipher))
+        }
+        v => Err(miette!(
+            "Wallet file format version {} is not supported.  This wallet supports up to version {}.",
+            v,
+            WALLET_FORMAT_VERSION
+        )),
+    }
+}
+
+/// Parses and decrypts bytes written by encode_wallet() with cipher.
+fn decrypt_wallet(bytes: &[u8], cipher: &WalletCipher) -> Result<Wallet> {
+    let body = bytes
+        .strip_prefix(WALLET_MAGIC)
+        .and_then(|b| b.strip_prefix(&WALLET_FORMAT_VERSION.to_le_bytes()[..]))
+        .ok_or_else(|| {
+            miette!(
+                "Wallet file is not format version {}",
+                WALLET_FORMAT_VERSION
+            )
+        })?;
+    let encrypted: EncryptedWallet = bincode::deserialize(body).into_diagnostic()?;
+    if encrypted.salt != cipher.salt {
+        return Err(miette!("Wallet file is encrypted with another passphrase"));
+    }
+    rmp_serde::from_slice(&cipher.decrypt(&encrypted)?).into_diagnostic()
+}
+
+/// Reads a version 0 (headerless) wallet file.  These hold the Wallet as
+/// bincode, either in plain text or inside an EncryptedWallet.
+fn migrate_v0(body: &[u8], passphrase: &str) -> Result<(Wallet, WalletCipher)> {
+    // unencrypted files predate passphrases.  The passphrase just
+    // entered will protect the wallet from now on.
+    let (v0, cipher) = match bincode::deserialize::<WalletV0>(body) {
+        Ok(v0) => (v0, WalletCipher::new(passphrase)?),
+        Err(_) => {
+            let encrypted: EncryptedWallet = bincode::deserialize(body).into_diagnostic()?;
+            let cipher = WalletCipher::derive(passphrase, encrypted.salt)?;
+            let plaintext = cipher.decrypt(&encrypted)?;
+            (bincode::deserialize(&plaintext).into_diagnostic()?, cipher)
+        }
+    };
+    println!(
+        "Migrating wallet from format version 0 to {}.\n",
+        WALLET_FORMAT_VERSION
+    );
+    Ok((v0.into(), cipher))
+}
+
+/// Every journal file begins with this magic, followed by the sha256 hash
+/// of the wallet file that its entries apply to.
+const JOURNAL_MAGIC: &[u8; 8] = b"SNDBCJNL";
+
+/// Number of journal entries after which the journal is compacted into
+/// the wallet file.
+const JOURNAL_COMPACT_ENTRIES: usize = 1000;
+
+/// A journal entry, holding everything written by one commit.
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    dbcs: Vec<DbcInfo>,
+    keys: Vec<SerdeSecret<SecretKey>>,
+
+    /// the fields other than dbcs and keys, from Wallet::meta_bytes().
+    meta: Option<Vec<u8>>,
+}
+
+impl JournalEntry {
+    fn apply(self, wallet: &mut Wallet) -> Result<()> {
+        if let Some(meta) = self.meta {
+            let mut updated: Wallet = rmp_serde::from_slice(&meta).into_diagnostic()?;
+            updated.dbcs = std::mem::take(&mut wallet.dbcs);
+            updated.keys = std::mem::take(&mut wallet.keys);
+            *wallet = updated;
+        }
+        for dinfo in self.dbcs.into_iter() {
+            wallet.dbcs.insert(dinfo.dbc.hash(), dinfo);
+        }
+        for sk in self.keys.into_iter() {
+            wallet.keys.insert(sk.inner().public_key(), sk);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the wallet in a single file, plus a journal of the changes made
+/// since it was last written.
+///
+/// Each commit appends one entry to the journal at `<wallet>.journal`.  The
+/// journal is compacted, by rewriting the wallet file and starting a new
+/// journal, once it reaches JOURNAL_COMPACT_ENTRIES and on every save().
+struct FileStore {
+    path: PathBuf,
+    backups: usize,
+    cipher: Option<WalletCipher>,
+
+    /// the cipher the backups are encrypted with, if the passphrase has
+    /// changed since they were written.
+    old_cipher: Option<WalletCipher>,
+
+    /// journal open for appending, if it applies to the wallet file.
+    journal: Option<std::fs::File>,
+    journal_entries: usize,
+}
+
+impl FileStore {
+    fn new(path: &Path, backups: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            backups,
+            cipher: None,
+            old_cipher: None,
+            journal: None,
+            journal_entries: 0,
+        }
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        suffixed_path(&self.path, "journal")
+    }
+
+    fn journal_header(wallet_bytes: &[u8]) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+        let mut header = JOURNAL_MAGIC.to_vec();
+        header.extend_from_slice(&Sha256::digest(wallet_bytes));
+        header
+    }
+
+    /// Writes the wallet to a temp file and syncs it before it atomically
+    /// replaces path, so a crash can never leave a partial wallet.  If
+    /// rotate is set, the previous file is first rotated into the backups.
+    ///
+    /// A new, empty journal is then started.  Should we crash before that,
+    /// the old journal no longer matches the wallet file and is ignored.
+    fn write(&mut self, wallet: &mut Wallet, rotate: bool) -> Result<()> {
+        use std::io::Write;
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
+        let bytes = encode_wallet(wallet, cipher)?;
+
+        let tmp_path = suffixed_path(&self.path, "tmp");
+        let mut file = std::fs::File::create(&tmp_path).into_diagnostic()?;
+        file.write_all(&bytes).into_diagnostic()?;
+        file.sync_all().into_diagnostic()?;
+        drop(file);
+
+        if rotate {
+            rotate_backups(&self.path, self.backups)?;
+        }
+        std::fs::rename(&tmp_path, &self.path).into_diagnostic()?;
+        sync_parent_dir(&self.path)?;
+        wallet.take_changes();
+
+        if let Some(old_cipher) = self.old_cipher.take() {
+            self.rekey_backups(&old_cipher)?;
+        }
+
+        self.journal = None;
+        let tmp_path = suffixed_path(&self.journal_path(), "tmp");
+        let mut file = std::fs::File::create(&tmp_path).into_diagnostic()?;
+        file.write_all(&Self::journal_header(&bytes))
+            .into_diagnostic()?;
+        file.sync_all().into_diagnostic()?;
+        drop(file);
+        std::fs::rename(&tmp_path, self.journal_path()).into_diagnostic()?;
+        sync_parent_dir(&self.path)?;
+
+        self.journal = Some(
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(self.journal_path())
+                .into_diagnostic()?,
+        );
+        self.journal_entries = 0;
+        Ok(())
+    }
+
+    /// Re-encrypts the backups written under old_cipher with the current
+    /// cipher, so that a changed passphrase no longer opens them.  A backup
+    /// that old_cipher can not decrypt is deleted instead.
+    fn rekey_backups(&self, old_cipher: &WalletCipher) -> Result<()> {
+        use std::io::Write;
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
+
+        for n in 1..=self.backups {
+            let backup_path = suffixed_path(&self.path, &n.to_string());
+            if !backup_path.exists() {
+                continue;
+            }
+            let rekeyed = std::fs::read(&backup_path)
+                .into_diagnostic()
+                .and_then(|bytes| decrypt_wallet(&bytes, old_cipher))
+                .and_then(|wallet| encode_wallet(&wallet, cipher));
+            match rekeyed {
+                Ok(bytes) => {
+                    let tmp_path = suffixed_path(&backup_path, "tmp");
+                    let mut file = std::fs::File::create(&tmp_path).into_diagnostic()?;
+                    file.write_all(&bytes).into_diagnostic()?;
+                    file.sync_all().into_diagnostic()?;
+                    drop(file);
+                    std::fs::rename(&tmp_path, &backup_path).into_diagnostic()?;
+                }
+                Err(_) => std::fs::remove_file(&backup_path).into_diagnostic()?,
+            }
+        }
+        sync_parent_dir(&self.path)
+    }
+
+    /// Loads and decrypts a single wallet file.  Also returns the file's
+    /// bytes, to match against the journal.
+    fn load_file(path: &Path, passphrase: &str) -> Result<(Wallet, WalletCipher, Vec<u8>)> {
+        let bytes = std::fs::read(path).into_diagnostic()?;
+        let (wallet, cipher) = decode_wallet(&bytes, passphrase)?;
+        Ok((wallet, cipher, bytes))
+    }
+
+    /// Applies the journal entries to wallet, if the journal was started
+    /// from wallet_bytes, and opens the journal for appending.
+    ///
+    /// An entry that was only partly written, because we crashed while
+    /// writing it, can only be the last, and is discarded.  A complete entry
+    /// that can not be read is an error, so that load falls back to a backup.
+    fn replay_journal(
+        &mut self,
+        wallet: &mut Wallet,
+        cipher: &WalletCipher,
+        wallet_bytes: &[u8],
+    ) -> Result<()> {
+        let bytes = match std::fs::read(self.journal_path()) {
+            Ok(bytes) => bytes,
+            Err(_) => return Ok(()),
+        };
+        let body = match bytes.strip_prefix(&Self::journal_header(wallet_bytes)[..]) {
+            Some(body) => body,
+            None => return Ok(()),
+        };
+
+        let mut pos = 0;
+        let mut entries = 0;
+        while let Some(len_bytes) = body.get(pos..pos + 4) {
+            let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]])
+                as usize;
+            let sealed = match body.get(pos + 4..pos + 4 + len) {
+                Some(sealed) => sealed,
+                None => break,
+            };
+            let entry: JournalEntry = rmp_serde::from_slice(&cipher.open(sealed)?)
+                .map_err(|e| miette!("Wallet journal entry {} is corrupt: {}", entries, e))?;
+            entry.apply(wallet)?;
+            pos += 4 + len;
+            entries += 1;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(self.journal_path())
+            .into_diagnostic()?;
+        if pos < body.len() {
+            println!(
+                "Warning: discarding {} bytes of incomplete wallet journal.\n",
+                body.len() - pos
+            );
+            file.set_len((bytes.len() - body.len() + pos) as u64)
+                .into_diagnostic()?;
+        }
+        self.journal = Some(file);
+        self.journal_entries = entries;
+        Ok(())
+    }
+
+    /// Appends the wallet's changes to the journal, as one entry.
+    fn append(&mut self, wallet: &mut Wallet) -> Result<()> {
+        use std::io::Write;
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
+        let journal = self
+            .journal
+            .as_mut()
+            .ok_or_else(|| miette!("Wallet journal is not open"))?;
+
+        let mut entry = JournalEntry {
+            dbcs: vec![],
+            keys: vec![],
+            meta: None,
+        };
+        for change in wallet.changes.clone().iter() {
+            match change {
+                WalletChange::Dbc(dbc_hash) => {
+                    entry.dbcs.extend(wallet.dbcs.get(dbc_hash).cloned())
+                }
+                WalletChange::Key(pk) => entry.keys.extend(
+                    wallet
+                        .keys
+                        .get(pk)
+                        .map(|sk| SerdeSecret(sk.inner().clone())),
+                ),
+                WalletChange::Meta => entry.meta = Some(wallet.meta_bytes()?),
+            }
+        }
+
+        let sealed = cipher.seal(&rmp_serde::to_vec_named(&entry).into_diagnostic()?)?;
+        let mut bytes = (sealed.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&sealed);
+
+        let result = journal
+            .write_all(&bytes)
+            .and_then(|_| journal.sync_data())
+            .into_diagnostic();
+        if result.is_ok() {
+            self.journal_entries += 1;
+            wallet.take_changes();
+        } else {
+            // the journal may now end with a partial entry, so it must
+            // not be appended to.  The next commit compacts instead.
+            self.journal = None;
+        }
+        result
+    }
+}
+
+impl WalletStore for FileStore {
+    fn exists(&self) -> bool {
+        self.path.exists() || suffixed_path(&self.path, "1").exists()
+    }
+
+    /// Loads the wallet file and replays its journal.  If the file or its
+    /// journal cannot be read or decrypted, falls back to the newest backup
+    /// that can be.
+    fn load(&mut self, passphrase: &str) -> Result<Wallet> {
+        let err = match Self::load_file(&self.path, passphrase) {
+            Ok((mut wallet, cipher, bytes)) => {
+                match self.replay_journal(&mut wallet, &cipher, &bytes) {
+                    Ok(()) => {
+                        self.cipher = Some(cipher);
+                        return Ok(wallet);
+                    }
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+
+        for n in 1..=self.backups {
+            let backup_path = suffixed_path(&self.path, &n.to_string());
+            if !backup_path.exists() {
+                continue;
+            }
+            if let Ok((wallet, cipher, _)) = Self::load_file(&backup_path, passphrase) {
+                println!(
+                    "Warning: unable to read {} ({}).\nLoaded backup {} instead.\n",
+                    self.path.display(),
+                    err,
+                    backup_path.display()
+                );
+                self.cipher = Some(cipher);
+                return Ok(wallet);
+            }
+        }
+        Err(err)
+    }
+
+    /// Saves the wallet, rotating the previous file into up to `backups`
+    /// numbered backups.
+    fn save(&mut self, wallet: &mut Wallet) -> Result<()> {
+        self.write(wallet, true)
+    }
+
+    /// Appends changes to the journal, compacting it if it is full or was
+    /// never started.  Backups are not rotated when compacting, else
+    /// routine commits would push an older backup out.
+    fn commit(&mut self, wallet: &mut Wallet) -> Result<()> {
+        if wallet.changes.is_empty() {
+            return Ok(());
+        }
+        if self.journal.is_none() || self.journal_entries >= JOURNAL_COMPACT_ENTRIES {
+            return self.write(wallet, false);
+        }
+        self.append(wallet)
+    }
+
+    fn cipher(&self) -> Option<&WalletCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Replaces the cipher.  The backups are re-encrypted with it by the
+    /// next save().
+    fn set_cipher(&mut self, cipher: WalletCipher) {
+        if let Some(old_cipher) = self.cipher.replace(cipher) {
+            self.old_cipher.get_or_insert(old_cipher);
+        }
+    }
+}
+
+/// Keeps the encrypted wallet in memory only, for throwaway sessions
+/// (`--wallet-store memory`).  Nothing survives exit.
+#[derive(Default)]
+struct MemoryStore {
+    bytes: Option<Vec<u8>>,
+    cipher: Option<WalletCipher>,
+}
+
+impl WalletStore for MemoryStore {
+    fn exists(&self) -> bool {
+        self.bytes.is_some()
+    }
+
+    fn load(&mut self, passphrase: &str) -> Result<Wallet> {
+        let bytes = self
+            .bytes
+            .as_ref()
+            .ok_or_else(|| miette!("No wallet has been saved"))?;
+        let (wallet, cipher) = decode_wallet(bytes, passphrase)?;
+        self.cipher = Some(cipher);
+        Ok(wallet)
+    }
+
+    fn save(&mut self, wallet: &mut Wallet) -> Result<()> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
+        self.bytes = Some(encode_wallet(wallet, cipher)?);
+        wallet.take_changes();
+        Ok(())
+    }
+
+    fn cipher(&self) -> Option<&WalletCipher> {
+        self.cipher.as_ref()
+    }
+
+    fn set_cipher(&mut self, cipher: WalletCipher) {
+        self.cipher = Some(cipher);
+    }
+}
+
+/// Keeps the wallet in a sled database, with each Dbc and key as its own
+/// encrypted record.  Adding a Dbc or marking one spent rewrites only that
+/// record, not the whole wallet.
+///
+/// Trees:
+///   meta:  "header" => magic, format version and salt.
+///          "wallet" => every other Wallet field.
+///   dbcs:  dbc hash => DbcInfo
+///   keys:  public key => secret key
+struct SledStore {
+    db: sled::Db,
+    meta: sled::Tree,
+    dbcs: sled::Tree,
+    keys: sled::Tree,
+    cipher: Option<WalletCipher>,
+
+    /// set when the cipher has changed, so every record must be rewritten.
+    rekeyed: bool,
+}
+
+impl SledStore {
+    const HEADER: &'static [u8] = b"header";
+    const WALLET: &'static [u8] = b"wallet";
+
+    fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path).into_diagnostic()?;
+        Ok(Self {
+            meta: db.open_tree("meta").into_diagnostic()?,
+            dbcs: db.open_tree("dbcs").into_diagnostic()?,
+            keys: db.open_tree("keys").into_diagnostic()?,
+            cipher: None,
+            rekeyed: false,
+            db,
+        })
+    }
+
+    fn header(cipher: &WalletCipher) -> Vec<u8> {
+        let mut header = WALLET_MAGIC.to_vec();
+        header.extend_from_slice(&WALLET_FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&cipher.salt);
+        header
+    }
 
-// axes:
-//  spent/unspent
-//  received/sent        (sent to self would be both sent+received)
-//  bearer/owned
-//  reissued_by_me/reissued_by_other
+    fn sealed_dbc(cipher: &WalletCipher, dinfo: &DbcInfo) -> Result<Vec<u8>> {
+        cipher.seal(&rmp_serde::to_vec_named(dinfo).into_diagnostic()?)
+    }
 
-//Unspent:
-// 1. owned dbcs for which owner matches one of my keys.            (received)
-// 2. owned dbcs for which owner does not match one of my keys.     (sent)
-// 3. bearer dbcs                                                   (received)
+    fn sealed_key(cipher: &WalletCipher, sk: &SerdeSecret<SecretKey>) -> Result<Vec<u8>> {
+        cipher.seal(&rmp_serde::to_vec_named(sk).into_diagnostic()?)
+    }
 
-//Spent:
-// 1. owned dbcs for which owner matches one of my keys.
-// 2. owned dbcs for which owner does not match one of my keys.
-// 3. bearer dbcs
+    /// Applies batches of writes to all three trees atomically, then flushes.
+    fn apply(&self, meta: sled::Batch, dbcs: sled::Batch, keys: sled::Batch) -> Result<()> {
+        use sled::transaction::{TransactionError, Transactional};
 
-#[derive(Default, Serialize, Deserialize)]
-struct Wallet {
-    dbcs: HashMap<[u8; 32], DbcInfo>,
-    keys: BTreeMap<PublicKey, SerdeSecret<SecretKey>>,
+        (&self.meta, &self.dbcs, &self.keys)
+            .transaction(|(m, d, k)| {
+                m.apply_batch(&meta)?;
+                d.apply_batch(&dbcs)?;
+                k.apply_batch(&keys)?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<()>| miette!("Unable to write wallet: {:?}", e))?;
+        self.db.flush().into_diagnostic()?;
+        Ok(())
+    }
 }
 
-impl Wallet {
-    fn unspent(&self) -> BTreeMap<&[u8; 32], &DbcInfo> {
-        self.dbcs
-            .iter()
-            .filter(|(_, d)| d.spent.is_none())
-            .collect()
+impl WalletStore for SledStore {
+    fn exists(&self) -> bool {
+        matches!(self.meta.contains_key(Self::HEADER), Ok(true))
     }
 
-    fn addkey(&mut self, sk: SecretKey) {
-        self.keys.insert(sk.public_key(), SerdeSecret(sk));
-    }
+    fn load(&mut self, passphrase: &str) -> Result<Wallet> {
+        let header = self
+            .meta
+            .get(Self::HEADER)
+            .into_diagnostic()?
+            .ok_or_else(|| miette!("Wallet database has no header"))?;
+        let (version, salt) = match header.strip_prefix(WALLET_MAGIC) {
+            Some([v0, v1, salt @ ..]) if salt.len() == 16 => (u16::from_le_bytes([*v0, *v1]), salt),
+            _ => return Err(miette!("Wallet database header is invalid")),
+        };
+        if version != WALLET_FORMAT_VERSION {
+            return Err(miette!(
+                "Wallet database format version {} is not supported.  This wallet supports up to version {}.",
+                version,
+                WALLET_FORMAT_VERSION
+            ));
+        }
+        let mut salt_bytes = [0u8; 16];
+        salt_bytes.copy_from_slice(salt);
+        let cipher = WalletCipher::derive(passphrase, salt_bytes)?;
 
-    // fn spent(&self) -> BTreeMap<&[u8; 32], &DbcInfo> {
-    //     self.dbcs
-    //         .iter()
-    //         .filter(|(_, d)| d.spent.is_some())
-    //         .collect()
-    // }
+        let sealed = self
+            .meta
+            .get(Self::WALLET)
+            .into_diagnostic()?
+            .ok_or_else(|| miette!("Wallet database is missing its wallet record"))?;
+        let mut wallet: Wallet = rmp_serde::from_slice(&cipher.open(&sealed)?).into_diagnostic()?;
 
-    fn mark_spent(&mut self, dbc_hash: &[u8; 32]) {
-        if let Some(dbc) = self.dbcs.get_mut(dbc_hash) {
-            dbc.spent = Some(chrono::Utc::now())
+        for entry in self.dbcs.iter() {
+            let (_, sealed) = entry.into_diagnostic()?;
+            let dinfo: DbcInfo = rmp_serde::from_slice(&cipher.open(&sealed)?).into_diagnostic()?;
+            wallet.dbcs.insert(dinfo.dbc.hash(), dinfo);
         }
+        for entry in self.keys.iter() {
+            let (_, sealed) = entry.into_diagnostic()?;
+            let sk: SerdeSecret<SecretKey> =
+                rmp_serde::from_slice(&cipher.open(&sealed)?).into_diagnostic()?;
+            wallet.keys.insert(sk.inner().public_key(), sk);
+        }
+
+        self.cipher = Some(cipher);
+        Ok(wallet)
     }
 
-    fn add_dbc(&mut self, dbc: Dbc, notes: Option<String>, sent: bool) -> Result<DbcInfo> {
-        if dbc.is_bearer() {
-            self.addkey(dbc.owner_base().secret_key().into_diagnostic()?);
+    /// Rewrites every record if the passphrase changed or the database is
+    /// new.  Otherwise the records are current but for the wallet's changes,
+    /// so just those are committed.
+    fn save(&mut self, wallet: &mut Wallet) -> Result<()> {
+        if !self.rekeyed && self.exists() {
+            return self.commit(wallet);
         }
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
 
-        let dbc_hash = dbc.hash();
-        let dbc_info = DbcInfo {
-            dbc,
-            received: chrono::Utc::now(),
-            spent: if sent { Some(chrono::Utc::now()) } else { None },
-            notes: notes.unwrap_or_else(|| "".to_string()),
-        };
-        self.dbcs.insert(dbc_hash, dbc_info.clone());
+        let mut meta = sled::Batch::default();
+        meta.insert(Self::HEADER, Self::header(cipher));
+        meta.insert(Self::WALLET, cipher.seal(&wallet.meta_bytes()?)?);
+
+        let mut dbcs = sled::Batch::default();
+        for key in self.dbcs.iter().keys() {
+            dbcs.remove(key.into_diagnostic()?);
+        }
+        for (dbc_hash, dinfo) in wallet.dbcs.iter() {
+            dbcs.insert(&dbc_hash[..], Self::sealed_dbc(cipher, dinfo)?);
+        }
+
+        let mut keys = sled::Batch::default();
+        for key in self.keys.iter().keys() {
+            keys.remove(key.into_diagnostic()?);
+        }
+        for (pk, sk) in wallet.keys.iter() {
+            keys.insert(&pk.to_bytes()[..], Self::sealed_key(cipher, sk)?);
+        }
 
-        Ok(dbc_info)
+        self.apply(meta, dbcs, keys)?;
+        wallet.take_changes();
+        self.rekeyed = false;
+        Ok(())
     }
 
-    async fn save(&mut self, path: &Path) -> Result<()> {
-        use std::io::Write;
-        let bytes = bincode::serialize(&self).into_diagnostic()?;
+    /// Writes only the records that changed.
+    fn commit(&mut self, wallet: &mut Wallet) -> Result<()> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
 
-        let mut file = std::fs::File::create(&path).into_diagnostic()?;
-        file.write(&bytes).into_diagnostic()?;
+        let mut meta = sled::Batch::default();
+        let mut dbcs = sled::Batch::default();
+        let mut keys = sled::Batch::default();
+        let changes = wallet.take_changes();
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for change in changes.iter() {
+            match change {
+                WalletChange::Dbc(dbc_hash) => match wallet.dbcs.get(dbc_hash) {
+                    Some(dinfo) => dbcs.insert(&dbc_hash[..], Self::sealed_dbc(cipher, dinfo)?),
+                    None => dbcs.remove(&dbc_hash[..]),
+                },
+                WalletChange::Key(pk) => match wallet.keys.get(pk) {
+                    Some(sk) => keys.insert(&pk.to_bytes()[..], Self::sealed_key(cipher, sk)?),
+                    None => keys.remove(&pk.to_bytes()[..]),
+                },
+                WalletChange::Meta => {
+                    meta.insert(Self::HEADER, Self::header(cipher));
+                    meta.insert(Self::WALLET, cipher.seal(&wallet.meta_bytes()?)?);
+                }
+            }
+        }
 
+        if let Err(e) = self.apply(meta, dbcs, keys) {
+            // keep the changes, so they are retried by the next commit.
+            wallet.changes.extend(changes);
+            return Err(e);
+        }
         Ok(())
     }
 
-    async fn load(path: &Path) -> Result<Self> {
-        use std::io::Read;
+    fn cipher(&self) -> Option<&WalletCipher> {
+        self.cipher.as_ref()
+    }
+
+    fn set_cipher(&mut self, cipher: WalletCipher) {
+        self.cipher = Some(cipher);
+        self.rekeyed = true;
+    }
+}
+
+/// Exclusive advisory lock on a wallet file, held while the wallet is open.
+///
+/// The lock is taken on a companion `.lock` file that records the pid of its
+/// holder.  The OS releases the lock when its holder exits, even by crashing,
+/// so a lock file that we are able to lock was left behind by a dead process.
+///
+/// The lock file is never deleted.  Were it removed while another process
+/// waits on it, that process and a third could each lock a different file at
+/// the same path.
+struct WalletLock {
+    file: std::fs::File,
+}
+
+impl WalletLock {
+    fn acquire(wallet_path: &Path) -> Result<Self> {
+        use fs2::FileExt;
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let path = suffixed_path(wallet_path, "lock");
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .into_diagnostic()?;
+
+        let mut holder = String::new();
+        let _ = file.read_to_string(&mut holder);
+        let holder = holder.trim();
 
-        let mut file = std::fs::File::open(&path).into_diagnostic()?;
-        let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes).into_diagnostic()?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(miette!(
+                "Wallet {} is already open in another process (pid {}).",
+                wallet_path.display(),
+                if holder.is_empty() { "unknown" } else { holder }
+            ));
+        }
+        if !holder.is_empty() {
+            println!(
+                "Recovered stale lock on {} left by process {}.",
+                wallet_path.display(),
+                holder
+            );
+        }
+
+        file.set_len(0).into_diagnostic()?;
+        file.seek(SeekFrom::Start(0)).into_diagnostic()?;
+        write!(file, "{}", std::process::id()).into_diagnostic()?;
+        file.sync_all().into_diagnostic()?;
+
+        Ok(Self { file })
+    }
+}
 
-        Ok(bincode::deserialize(&bytes).into_diagnostic()?)
+impl Drop for WalletLock {
+    fn drop(&mut self) {
+        // cleared so the next holder does not report a stale lock.
+        let _ = self.file.set_len(0);
+        let _ = fs2::FileExt::unlock(&self.file);
     }
 }
 
@@ -183,9 +2251,15 @@ struct WalletNodeClient {
 
     wallet: Wallet,
 
+    /// persists the wallet
+    store: Box<dyn WalletStore>,
+
     spentbook_nodes: BTreeMap<XorName, SocketAddr>,
     spentbook_pks: Option<PublicKeySet>,
 
+    /// decoys loaded from a file, for building reissues while offline.
+    decoys: Vec<DecoyInput>,
+
     /// for communicating with others
     wallet_endpoint: Endpoint,
 }
@@ -222,11 +2296,20 @@ async fn do_main() -> Result<()> {
     )
     .into_diagnostic()?;
 
+    // held until we exit, so no other process can open the wallet meanwhile.
+    let _wallet_lock = WalletLock::acquire(&config.wallet_file)?;
+    let mut store = config
+        .wallet_store
+        .open(&config.wallet_file, config.wallet_backups)?;
+    let wallet = open_wallet(store.as_mut(), &config.wallet_file)?;
+
     let my_node = WalletNodeClient {
-        wallet: Wallet::load(&config.wallet_file).await.unwrap_or_default(),
+        wallet,
+        store,
         config,
         spentbook_nodes: Default::default(),
         spentbook_pks: None,
+        decoys: Default::default(),
         wallet_endpoint,
     };
 
@@ -239,6 +2322,87 @@ async fn do_main() -> Result<()> {
     Ok(())
 }
 
+/// Loads the wallet from store, prompting for its passphrase.  If the store
+/// is empty, a new empty wallet is created and the user chooses a passphrase.
+fn open_wallet(store: &mut dyn WalletStore, path: &Path) -> Result<Wallet> {
+    if store.exists() {
+        let passphrase = readline_password(&format!("Passphrase for {}: ", path.display()))?;
+        let mut wallet = store
+            .load(&passphrase)
+            .map_err(|e| miette!("Unable to open wallet {}: {}", path.display(), e))?;
+
+        if wallet.seed.is_none() {
+            println!("This wallet has no recovery phrase.  Generating one now.");
+            println!("Keys created before now can not be restored from it.");
+            wallet.seed = Some(new_seed()?);
+            wallet.changes.insert(WalletChange::Meta);
+            store.save(&mut wallet)?;
+        }
+        wallet.reindex();
+        Ok(wallet)
+    } else {
+        println!("Creating new wallet {}", path.display());
+        let mut wallet = Wallet::default();
+        wallet.seed = loop {
+            match readline_prompt("[n]ew or [r]estore from recovery phrase: ")?.as_str() {
+                "n" => break Some(new_seed()?),
+                "r" => break Some(restore_seed()?),
+                _ => println!("Invalid selection\n"),
+            }
+        };
+
+        store.set_cipher(WalletCipher::new(&readline_new_password()?)?);
+        store.save(&mut wallet)?;
+        Ok(wallet)
+    }
+}
+
+/// Generates a random wallet seed and displays its recovery phrase.
+fn new_seed() -> Result<Vec<u8>> {
+    let mut entropy = [0u8; 32];
+    rng::thread_rng().fill_bytes(&mut entropy);
+    let mnemonic = bip39::Mnemonic::from_entropy(&entropy).map_err(|e| miette!("{}", e))?;
+
+    println!(
+        "\nRecovery phrase:\n\n  {}\n\n  Important!  Write this down and keep it safe.  It will not be shown again.\n  Together with your Dbcs, it can restore every key in this wallet.\n",
+        mnemonic
+    );
+    Ok(mnemonic.to_seed_normalized("").to_vec())
+}
+
+/// Reads a recovery phrase and returns its seed.  Keys derived from the seed
+/// are recovered as Dbcs owned by them are deposited.
+fn restore_seed() -> Result<Vec<u8>> {
+    let phrase = readline_prompt("Recovery phrase: ")?;
+    let mnemonic = bip39::Mnemonic::parse_normalized(&phrase.to_lowercase())
+        .map_err(|e| miette!("Invalid recovery phrase: {}", e))?;
+    Ok(mnemonic.to_seed_normalized("").to_vec())
+}
+
+/// Derives the secret key at index from seed.
+///
+/// Hashes that are not a valid scalar for the curve are skipped by
+/// re-hashing with an incrementing counter.
+fn derive_secret_key(seed: &[u8], index: u64) -> SecretKey {
+    use sha2::{Digest, Sha256};
+
+    let mut counter: u32 = 0;
+    loop {
+        let hash = Sha256::new()
+            .chain_update(b"sn_dbc_examples wallet key")
+            .chain_update(seed)
+            .chain_update(index.to_le_bytes())
+            .chain_update(counter.to_le_bytes())
+            .finalize();
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hash);
+        if let Ok(secret_key) = SecretKey::from_bytes(bytes) {
+            return secret_key;
+        }
+        counter += 1;
+    }
+}
+
 impl WalletNodeClient {
     async fn run(mut self) -> Result<()> {
         print_logo();
@@ -259,27 +2423,58 @@ impl WalletNodeClient {
                         continue;
                     };
                     let result = match cmd {
-                        "balance" => self.cli_balance(),
-                        "deposit" => self.cli_deposit(),
+                        "balance" => self.cli_balance(args),
+                        "accounts" => self.cli_accounts(args),
+                        "contacts" => self.cli_contacts(args),
+                        "watch" => self.cli_watch(args),
+                        "deposit" => self.cli_deposit(args.next()),
                         "issue_genesis" => self.cli_issue_genesis().await,
                         "keys" => self.cli_keys(),
-                        "reissue" => self.cli_reissue().await,
-                        "unspent" => self.cli_unspent(),
+                        "reissue" => self.cli_reissue(args).await,
+                        "reissue_manual" => self.cli_reissue_manual(args).await,
+                        "reissue_multi" => self.cli_reissue_multi(args).await,
+                        "reissue_autogen" => self.cli_reissue_autogen(args).await,
+                        "consolidate" => self.cli_consolidate(args).await,
+                        "pending" => self.cli_pending(args).await,
+                        "tx" => self.cli_tx(args).await,
+                        "unspent" => self.cli_unspent(args),
+                        "spent" => self.cli_spent(args),
+                        "history" => self.cli_history(args.next()),
+                        "notes" => self.cli_notes(args.next()),
+                        "tag" => self.cli_tag(args, true),
+                        "untag" => self.cli_tag(args, false),
+                        "search" => self.cli_search(args),
+                        "strategy" => self.cli_strategy(args.next()),
                         // "reissue_auto" => self.cli_reissue_auto(),
-                        // "validate" => self.cli_validate(),
-                        "newkey" => self.cli_newkey(),
+                        "validate" => self.cli_validate(args.next()),
+                        "newkey" => self.cli_newkey(args),
                         // "newkeys" => self.cli_newkeys(),
-                        // "decode" => self.cli_decode(),
+                        "decode" => self.cli_decode(),
                         "join" => self.cli_join().await,
                         "save" => self.cli_save().await,
+                        "export" => self.cli_export(),
+                        "import" => self.cli_import(),
+                        "passwd" => self.cli_passwd().await,
                         "quit" | "exit" => break,
                         "help" => {
                             println!(
                                 "\nCommands:
   Network: [join]
-  Wallet:  [balance, deposit, issue_genesis, keys, newkey, reissue, unspent]
-  Other:   [save, exit, help]
-  future:  [spent, reissue_manual, reissue_autogen, decode, validate]"
+  Wallet:  [accounts, balance, consolidate, contacts, deposit, history, issue_genesis, keys, newkey, notes, pending, reissue, reissue_autogen, reissue_manual, reissue_multi, search, spent, strategy, tag, tx, unspent, untag, validate, watch]
+  Other:   [decode, export, import, passwd, save, exit, help]
+
+  balance, newkey and the reissue commands accept: account=<name>
+  reissue, reissue_multi and reissue_autogen accept: strategy=largest|smallest|exact|bearer|minimize.  strategy [<name>] shows or sets the default
+  reissue_autogen accepts: dbc=<hash> or amount=<amount>, denoms=pow2 (default) or a list, eg denoms=100x3,50,10x5
+  pending [show | resume | rollback] completes or cancels an interrupted reissue
+  tx [decoys | load_decoys | export | broadcast | finalize] moves a reissue between --offline and online wallets
+  deposit [force] validates the Dbc first.  force deposits it unvalidated when the spentbook key is not known
+  validate [<hash>] checks a pasted Dbc, or one of ours, against the spentbook key
+  notes, tag and untag take a Dbc hash prefix: notes <hash>, tag <hash> <tag>...
+  consolidate accepts: batch=<max inputs per transaction>, and the filters below
+  consolidate, search, spent and unspent accept filters: from=YYYY-MM-DD to=YYYY-MM-DD owner=mine|notmine|bearer|watch account=<name>
+                                                         tag=<tag> text=<text> min=<amount> max=<amount>
+                                                         (owner=notmine: search and spent only)"
                             );
                             Ok(())
                         }
@@ -288,6 +2483,9 @@ impl WalletNodeClient {
                     if let Err(msg) = result {
                         println!("\nError: {:?}\n", msg);
                     }
+                    if let Err(msg) = self.store.commit(&mut self.wallet) {
+                        println!("\nError saving wallet: {:?}\n", msg);
+                    }
                 }
                 Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => break,
                 Err(e) => {
@@ -296,181 +2494,1103 @@ impl WalletNodeClient {
             }
             println!();
         }
-        self.wallet.save(&self.config.wallet_file).await
+        self.save().await
+    }
+
+    async fn save(&mut self) -> Result<()> {
+        self.store.save(&mut self.wallet)
     }
 
     async fn cli_save(&mut self) -> Result<()> {
-        self.wallet.save(&self.config.wallet_file).await
+        self.save().await
+    }
+
+    fn cli_export(&self) -> Result<()> {
+        let path = PathBuf::from(readline_prompt("Export to file: ")?);
+        if path.exists() && readline_prompt("File exists.  Overwrite? [y/n]: ")? != "y" {
+            return Ok(());
+        }
+
+        let ron = ron::ser::to_string_pretty(&self.wallet, ron::ser::PrettyConfig::default())
+            .into_diagnostic()?;
+        std::fs::write(&path, ron).into_diagnostic()?;
+
+        println!(
+            "Exported {} keys and {} Dbcs to {}.\n\n  Important!  The export is not encrypted and contains every secret key.\n  Store it securely.",
+            self.wallet.keys.len(),
+            self.wallet.dbcs.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    fn cli_import(&mut self) -> Result<()> {
+        let path = PathBuf::from(readline_prompt("Import from file: ")?);
+        let ron = std::fs::read_to_string(&path).into_diagnostic()?;
+        let other: Wallet = ron::from_str(&ron).into_diagnostic()?;
+
+        let (keys_added, dbcs_added) = self.wallet.merge(other);
+        println!(
+            "Imported {} new keys and {} new Dbcs.",
+            keys_added, dbcs_added
+        );
+        Ok(())
+    }
+
+    async fn cli_passwd(&mut self) -> Result<()> {
+        let current = readline_password("Current passphrase: ")?;
+        let cipher = self
+            .store
+            .cipher()
+            .ok_or_else(|| miette!("Wallet store has no passphrase set"))?;
+        if !cipher.matches(&current)? {
+            return Err(miette!("Incorrect passphrase"));
+        }
+
+        self.store
+            .set_cipher(WalletCipher::new(&readline_new_password()?)?);
+        self.save().await?;
+        println!("Passphrase changed.");
+        Ok(())
     }
 
     async fn process_config(&mut self) -> Result<()> {
+        if self.config.offline {
+            println!("Offline.  Reissues will be built, but not broadcast.");
+            self.spentbook_pks = self.wallet.pinned_spentbook_pks.clone();
+            return Ok(());
+        }
         self.join_spentbook_section(self.config.join_spentbook)
             .await?;
         Ok(())
     }
 
-    fn cli_newkey(&mut self) -> Result<()> {
-        let secret_key = crate::SecretKey::random();
+    fn cli_newkey<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let account = account_arg(args)?;
+        let secret_key = self
+            .wallet
+            .new_account_key(account.unwrap_or(DEFAULT_ACCOUNT))?;
 
         println!(
             "Receive PublicKey: {}",
             encode(&secret_key.public_key().to_bytes())
         );
-
-        self.wallet.addkey(secret_key);
         Ok(())
     }
 
-    fn cli_deposit(&mut self) -> Result<()> {
+    /// Deposits a pasted Dbc, once it is validated.  With force, a Dbc is
+    /// deposited without validation if the spentbook key is not known.
+    fn cli_deposit(&mut self, arg: Option<&str>) -> Result<()> {
+        let force = match arg {
+            None => false,
+            Some("force") => true,
+            Some(_) => return Err(miette!("Usage: deposit [force]")),
+        };
+        if self.spentbook_pks.is_none() && !force {
+            return Err(miette!("The spentbook key is not known, so the Dbc can not be validated.  Use 'join' first, or 'deposit force' to deposit it without validation."));
+        }
+
         let dbc: Dbc = from_le_hex(&readline_prompt_nl("Paste Dbc: ")?)?;
+        if self.spentbook_pks.is_some() {
+            self.validate_dbc(&dbc)?;
+        } else {
+            println!("Warning: depositing this Dbc without validation.");
+        }
         let notes = readline_prompt_default("Notes (optional): ", "")?;
         let n = if notes.is_empty() { None } else { Some(notes) };
         let dinfo = self.wallet.add_dbc(dbc, n, false)?;
 
-        let ownership = dinfo.ownership(&self.wallet.keys);
-        match ownership {
-            Ownership::Mine => {
-                if let Some(key) = self.wallet.keys.get(&dinfo.dbc.owner_base().public_key()) {
-                    let sk = key.inner().clone();
-                    let secrets = dinfo.dbc.amount_secrets(&sk).into_diagnostic()?;
-                    println!("Deposited {}", secrets.amount());
-                } else {
-                    return Err(miette!("Secret key not found"));
-                }
-            }
-            Ownership::Bearer => {
-                let secrets = dinfo.dbc.amount_secrets_bearer().into_diagnostic()?;
-                println!("Deposited {}.\n\n  Important!  Anyone can spend this bearer Dbc.\n  It should be reissued to an owned Dbc immediately.", secrets.amount());
+        let ownership = dinfo.ownership(&self.wallet);
+        let amount = || {
+            dinfo
+                .amount_secrets
+                .as_ref()
+                .map(|s| s.amount())
+                .ok_or_else(|| miette!("Unable to decrypt amount secrets of Dbc"))
+        };
+        match ownership {
+            Ownership::Mine => {
+                println!("Deposited {}", amount()?);
+            }
+            Ownership::Bearer => {
+                println!("Deposited {}.\n\n  Important!  Anyone can spend this bearer Dbc.\n  It should be reissued to an owned Dbc immediately.", amount()?);
+            }
+            Ownership::NotMine => {
+                println!("Added unknown Dbc.  This Dbc is owned by a third party.")
+            }
+            Ownership::WatchOnly => {
+                let secrets: AmountSecrets =
+                    from_le_hex(&readline_prompt_nl("Paste AmountSecrets: ")?)?;
+                if !amount_secrets_match(&dinfo.dbc, &secrets) {
+                    return Err(miette!("The AmountSecrets do not match the Dbc's amount commitment.  The Dbc was added without an amount."));
+                }
+                println!("Deposited {} (watch-only)", secrets.amount());
+                self.wallet.set_amount_secrets(&dinfo.dbc.hash(), secrets);
+            }
+        };
+        Ok(())
+    }
+
+    fn cli_balance<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let account = account_arg(args)?;
+        let balance = self.balance(account)?;
+        println!("Available balance: {}", balance);
+
+        if !self.wallet.watch_keys.is_empty() {
+            let watched: Amount = self
+                .unspent(account)?
+                .iter()
+                .filter(|u| u.secret_key.is_none())
+                .fold(0, |total: Amount, u| {
+                    total.saturating_add(u.amount_secrets.amount())
+                });
+            println!("Watch-only balance: {}", watched);
+        }
+        Ok(())
+    }
+
+    fn cli_watch<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+        match (args.next(), args.next()) {
+            (None | Some("list"), _) => {
+                println!("  -- Watched Keys -- ");
+                for pk in self.wallet.watch_keys.iter() {
+                    println!("  {}", encode(&pk.to_bytes()));
+                }
+            }
+            (Some("add"), Some(pk_hex)) => {
+                let public_key = public_key_from_hex(pk_hex)?;
+                if self.wallet.keys.contains_key(&public_key) {
+                    return Err(miette!(
+                        "We already hold the secret key for this public key"
+                    ));
+                }
+                self.wallet.watch(public_key);
+                println!("Watching {}", pk_hex);
+            }
+            (Some("remove"), Some(pk_hex)) => {
+                if !self.wallet.unwatch(&public_key_from_hex(pk_hex)?) {
+                    return Err(miette!("Key is not watched"));
+                }
+                println!("No longer watching {}", pk_hex);
+            }
+            _ => {
+                return Err(miette!(
+                    "Usage: watch [list | add <public_key> | remove <public_key>]"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn cli_accounts<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+        match (args.next(), args.next(), args.next()) {
+            (None | Some("list"), ..) => {
+                println!("  -- Accounts -- ");
+                let names = std::iter::once(DEFAULT_ACCOUNT)
+                    .chain(self.wallet.accounts.keys().map(|n| n.as_str()));
+                for name in names {
+                    println!("{}, balance: {}", name, self.balance(Some(name))?);
+                }
+            }
+            (Some("add"), Some(name), _) => {
+                self.wallet.add_account(name)?;
+                println!("Added account '{}'", name);
+            }
+            (Some("remove"), Some(name), _) => {
+                self.wallet.remove_account(name)?;
+                println!(
+                    "Removed account '{}'.  Its keys now belong to the {} account.",
+                    name, DEFAULT_ACCOUNT
+                );
+            }
+            (Some("assign"), Some(pk_hex), Some(name)) => {
+                let public_key = public_key_from_hex(pk_hex)?;
+                self.wallet.assign_key(public_key, name)?;
+                println!("Key assigned to account '{}'", name);
+            }
+            _ => {
+                return Err(miette!(
+                "Usage: accounts [list | add <name> | remove <name> | assign <public_key> <name>]"
+            ))
+            }
+        }
+        Ok(())
+    }
+
+    fn cli_contacts<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+        match (args.next(), args.next(), args.next()) {
+            (None | Some("list"), ..) => {
+                println!("  -- Contacts -- ");
+                for (alias, contact) in self.wallet.contacts.iter() {
+                    println!(
+                        "{}, public_key: {}, notes: {}",
+                        alias,
+                        encode(&contact.public_key.to_bytes()),
+                        contact.notes
+                    );
+                }
+            }
+            (Some("add"), Some(alias), Some(pk_hex)) => {
+                let public_key = public_key_from_hex(pk_hex)?;
+                let notes = readline_prompt_default("Notes (optional): ", "")?;
+                self.wallet.add_contact(alias, public_key, notes)?;
+                println!("Added contact '{}'", alias);
+            }
+            (Some("remove"), Some(alias), _) => {
+                self.wallet.remove_contact(alias)?;
+                println!("Removed contact '{}'", alias);
+            }
+            _ => {
+                return Err(miette!(
+                    "Usage: contacts [list | add <alias> <public_key> | remove <alias>]"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    async fn cli_reissue<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let mut kv = parse_kv_args(args, &["account", "strategy"])?;
+        let account = kv.remove("account");
+        let strategy = match kv.remove("strategy") {
+            Some(strategy) => strategy.parse()?,
+            None => self.wallet.strategy,
+        };
+        let balance = self.balance(account)?;
+        if balance == 0 {
+            println!("No funds available for reissue.");
+            return Ok(());
+        }
+
+        println!("Available balance: {}", balance);
+
+        let spend_amount = loop {
+            let amount: Amount = readline_prompt("Amount to spend: ")?
+                .parse()
+                .into_diagnostic()?;
+            if amount == 0 {
+                println!("  amount must be greater than zero.\n");
+                continue;
+            }
+            if amount <= balance {
+                break amount;
+            }
+            println!(
+                "  entered amount exceeds available balance of {}.\n",
+                balance
+            );
+        };
+
+        let inputs = self.select_inputs(account, strategy, spend_amount)?;
+        let account = account.unwrap_or(DEFAULT_ACCOUNT);
+        let owner = self.prompt_owner(account)?;
+        let notes = readline_prompt_default("Notes (optional): ", "")?;
+
+        let outputs = vec![ReissueOutput {
+            owner,
+            amount: spend_amount,
+        }];
+        self.reissue(inputs, outputs, account, notes).await
+    }
+
+    /// Reissues to any number of recipients in a single transaction.
+    async fn cli_reissue_multi<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let mut kv = parse_kv_args(args, &["account", "strategy"])?;
+        let account = kv.remove("account");
+        let strategy = match kv.remove("strategy") {
+            Some(strategy) => strategy.parse()?,
+            None => self.wallet.strategy,
+        };
+        let balance = self.balance(account)?;
+        if balance == 0 {
+            println!("No funds available for reissue.");
+            return Ok(());
+        }
+
+        println!("Available balance: {}", balance);
+        println!("Enter each recipient's amount and owner.  Enter an empty amount when done.\n");
+
+        let change_account = account.unwrap_or(DEFAULT_ACCOUNT);
+        let mut outputs: Vec<ReissueOutput> = Default::default();
+        let mut total: Amount = 0;
+        while outputs.len() < MAX_OUTPUTS {
+            let prompt = format!("Recipient {} amount: ", outputs.len() + 1);
+            let input = readline_prompt_default(&prompt, "")?;
+            if input.is_empty() {
+                break;
+            }
+            let amount: Amount = input.parse().into_diagnostic()?;
+            if amount == 0 {
+                println!("  amount must be greater than zero.\n");
+                continue;
+            }
+            let new_total = total
+                .checked_add(amount)
+                .ok_or_else(|| miette!("Total amount overflows"))?;
+            if new_total > balance {
+                println!(
+                    "  total of {} exceeds available balance of {}.\n",
+                    new_total, balance
+                );
+                continue;
+            }
+            let owner = self.prompt_owner(change_account)?;
+            total = new_total;
+            outputs.push(ReissueOutput { owner, amount });
+            if outputs.len() == MAX_OUTPUTS {
+                println!("  at most {} recipients are allowed.\n", MAX_OUTPUTS);
+            }
+        }
+        if outputs.is_empty() {
+            println!("No recipients entered.");
+            return Ok(());
+        }
+
+        let notes = readline_prompt_default("Notes (optional): ", "")?;
+        println!(
+            "Sending {} to {} recipients in one transaction.",
+            total,
+            outputs.len()
+        );
+
+        let inputs = self.select_inputs(account, strategy, total)?;
+        self.reissue(inputs, outputs, change_account, notes).await
+    }
+
+    /// Splits a Dbc, or an amount, into outputs of the given denominations,
+    /// each owned by a new key of ours.
+    async fn cli_reissue_autogen<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let mut kv = parse_kv_args(args, &["dbc", "amount", "denoms", "account", "strategy"])?;
+        let account = kv.remove("account");
+        let strategy = match kv.remove("strategy") {
+            Some(strategy) => strategy.parse()?,
+            None => self.wallet.strategy,
+        };
+
+        let (inputs, amount) = match (kv.remove("dbc"), kv.remove("amount")) {
+            (Some(_), Some(_)) => return Err(miette!("Specify either dbc= or amount=, not both")),
+            (Some(prefix), None) => {
+                let dbc_hash = self.wallet.find_by_prefix(prefix)?;
+                let input = self
+                    .spendable(account)?
+                    .into_iter()
+                    .find(|i| i.dbc.hash() == dbc_hash)
+                    .ok_or_else(|| miette!("That Dbc is spent, or we can not spend it"))?;
+                let amount = input.amount;
+                (vec![input], amount)
+            }
+            (None, amount) => {
+                let balance = self.balance(account)?;
+                println!("Available balance: {}", balance);
+                let amount: Amount = match amount {
+                    Some(amount) => amount.parse().into_diagnostic()?,
+                    None => readline_prompt("Amount to split: ")?
+                        .parse()
+                        .into_diagnostic()?,
+                };
+                if amount > balance {
+                    return Err(miette!("Amount exceeds available balance of {}", balance));
+                }
+                (self.select_inputs(account, strategy, amount)?, amount)
+            }
+        };
+
+        let denominations = match kv.remove("denoms") {
+            None | Some("pow2") => power_of_two_denominations(amount),
+            Some(list) => parse_denominations(list)?,
+        };
+        let total = denominations_total(&denominations)?;
+        if total == 0 {
+            return Err(miette!("Nothing to split"));
+        } else if total > amount {
+            return Err(miette!(
+                "Denominations total {} exceeds amount of {}",
+                total,
+                amount
+            ));
+        }
+        println!(
+            "Splitting {} into {} outputs: {}",
+            total,
+            denominations.len(),
+            denominations
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let account = account.unwrap_or(DEFAULT_ACCOUNT);
+        let mut outputs: Vec<ReissueOutput> = Default::default();
+        for amount in denominations.into_iter() {
+            let public_key = self.wallet.new_account_key(account)?.public_key();
+            outputs.push(ReissueOutput {
+                owner: Owner::from(public_key),
+                amount,
+            });
+        }
+        let notes = readline_prompt_default("Notes (optional): ", "")?;
+        self.reissue(inputs, outputs, account, notes).await
+    }
+
+    /// Merges our unspent Dbcs, or those matching a filter, into one owned
+    /// Dbc per batch of inputs.  Each account is consolidated separately, so
+    /// that funds stay in the account they were in.
+    async fn cli_consolidate<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let (batch_args, filter_args): (Vec<&str>, Vec<&str>) =
+            args.partition(|arg| arg.starts_with("batch="));
+        let batch_size = match parse_kv_args(batch_args.into_iter(), &["batch"])?.remove("batch") {
+            Some(size) => size
+                .parse()
+                .map_err(|_| miette!("Invalid batch size '{}'", size))?,
+            None => CONSOLIDATE_BATCH_SIZE,
+        };
+        if batch_size < 2 {
+            return Err(miette!("Batch size must be at least 2"));
+        }
+        let filter = DbcFilter::parse_unspent(filter_args.into_iter())?;
+
+        let mut by_account: BTreeMap<String, Vec<ReissueInput>> = Default::default();
+        for input in self.spendable(None)?.into_iter() {
+            let dinfo = &self.wallet.dbcs[&input.dbc.hash()];
+            if filter.matches(&self.wallet, dinfo, dinfo.received) {
+                let account = self.wallet.account_of(&input.dbc.owner_base().public_key());
+                by_account
+                    .entry(account.to_string())
+                    .or_default()
+                    .push(input);
             }
-            Ownership::NotMine => {
-                println!("Added unknown Dbc.  This Dbc is owned by a third party.")
-            }
-        };
+        }
+
+        let batches: Vec<(String, Vec<ReissueInput>)> = by_account
+            .iter()
+            .flat_map(|(account, inputs)| {
+                inputs
+                    .chunks(batch_size)
+                    .filter(|batch| batch.len() > 1)
+                    .map(move |batch| (account.clone(), batch.to_vec()))
+            })
+            .collect();
+        if batches.is_empty() {
+            println!("Nothing to consolidate.");
+            return Ok(());
+        }
+        let num_batches = batches.len();
+
+        for (n, (account, batch)) in batches.into_iter().enumerate() {
+            println!(
+                "\nBatch {} of {}: consolidating {} Dbcs of account '{}'",
+                n + 1,
+                num_batches,
+                batch.len(),
+                account
+            );
+            // with no outputs, everything returns to us as change.
+            self.reissue(batch, vec![], &account, "consolidate".to_string())
+                .await?;
+        }
         Ok(())
     }
 
-    fn cli_balance(&mut self) -> Result<()> {
-        let balance = self.balance()?;
-        println!("Available balance: {}", balance);
-        Ok(())
+    /// Chooses inputs totalling at least amount, using strategy.
+    fn select_inputs(
+        &self,
+        account: Option<&str>,
+        strategy: coin_selection::Strategy,
+        amount: Amount,
+    ) -> Result<Vec<ReissueInput>> {
+        let spendable = self.spendable(account)?;
+        let candidates: Vec<coin_selection::Candidate> = spendable
+            .iter()
+            .map(|input| coin_selection::Candidate {
+                amount: input.amount,
+                bearer: input.dbc.is_bearer(),
+            })
+            .collect();
+        Ok(coin_selection::select(strategy, &candidates, amount)?
+            .into_iter()
+            .map(|i| spendable[i].clone())
+            .collect())
     }
 
-    async fn cli_reissue(&mut self) -> Result<()> {
-        let balance = self.balance()?;
-        if balance == 0 {
+    /// Reissues from inputs chosen by the user, rather than by a coin
+    /// selection strategy.
+    async fn cli_reissue_manual<'a>(&mut self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let account = account_arg(args)?;
+        let spendable = self.spendable(account)?;
+        let account = account.unwrap_or(DEFAULT_ACCOUNT);
+        if spendable.is_empty() {
             println!("No funds available for reissue.");
             return Ok(());
         }
 
-        println!("Available balance: {}", balance);
+        println!("  -- Unspent Dbcs -- ");
+        for (idx, input) in spendable.iter().enumerate() {
+            println!(
+                "{}. {} --> amount: {} ({})",
+                idx,
+                encode(input.dbc.hash()),
+                input.amount,
+                if input.dbc.is_bearer() {
+                    Ownership::Bearer
+                } else {
+                    Ownership::Mine
+                }
+            );
+        }
+
+        let inputs: Vec<ReissueInput> = loop {
+            let line = readline_prompt("\nInputs to spend, eg 0,2,3: ")?;
+            match parse_indexes(&line, spendable.len()) {
+                Ok(indexes) => break indexes.into_iter().map(|i| spendable[i].clone()).collect(),
+                Err(e) => println!("  {}", e),
+            }
+        };
+        let inputs_amount = inputs.iter().try_fold(0, |total: Amount, i| {
+            total
+                .checked_add(i.amount)
+                .ok_or_else(|| miette!("Selected inputs total overflows"))
+        })?;
+        println!(
+            "Selected {} inputs totalling {}",
+            inputs.len(),
+            inputs_amount
+        );
 
         let spend_amount = loop {
             let amount: Amount = readline_prompt("Amount to spend: ")?
                 .parse()
                 .into_diagnostic()?;
-            if amount <= balance {
+            if amount == 0 {
+                println!("  amount must be greater than zero.\n");
+                continue;
+            }
+            if amount <= inputs_amount {
                 break amount;
             }
             println!(
-                "  entered amount exceeds available balance of {}.\n",
-                balance
+                "  entered amount exceeds selected inputs of {}.\n",
+                inputs_amount
             );
         };
+        println!("Change: {}", inputs_amount - spend_amount);
 
-        let owner_base = {
-            loop {
-                match readline_prompt("[b]earer or [o]wned: ")?.as_str() {
-                    "b" => {
-                        let secret_key = crate::SecretKey::random();
-                        self.wallet.addkey(secret_key.clone());
-                        break Owner::from(secret_key);
-                    }
-                    "o" => {
-                        let input = readline_prompt("Recipient's public key: ")?;
-                        let mut bytes = [0u8; 48];
-                        let d = decode(&input)?;
-                        bytes.copy_from_slice(&d);
-                        let public_key: PublicKey =
-                            PublicKey::from_bytes(bytes).into_diagnostic()?;
-                        break Owner::from(public_key);
-                    }
-                    _ => println!("Invalid selection\n"),
+        let owner = self.prompt_owner(account)?;
+        let notes = readline_prompt_default("Notes (optional): ", "")?;
+
+        let outputs = vec![ReissueOutput {
+            owner,
+            amount: spend_amount,
+        }];
+        self.reissue(inputs, outputs, account, notes).await
+    }
+
+    /// Prompts for the owner of a reissue output.  A bearer output is
+    /// given a new key of account.
+    fn prompt_owner(&mut self, account: &str) -> Result<Owner> {
+        loop {
+            match readline_prompt("[b]earer or [o]wned: ")?.as_str() {
+                "b" => return Ok(Owner::from(self.wallet.new_account_key(account)?)),
+                "o" => {
+                    let input = readline_prompt("Recipient's contact or public key: ")?;
+                    return Ok(Owner::from(self.wallet.recipient_key(&input)?));
                 }
+                _ => println!("Invalid selection\n"),
             }
-        };
-        let mut rng = rng::thread_rng();
-        let recip_owner_once = OwnerOnce::from_owner_base(owner_base, &mut rng);
+        }
+    }
+
+    /// Returns the unspent Dbcs we hold the keys to spend, optionally only
+    /// those of one account.
+    fn spendable(&self, account: Option<&str>) -> Result<Vec<ReissueInput>> {
+        // watch-only Dbcs have no secret key, so can never be inputs, and
+        // inputs of a pending reissue are reserved until it completes.
+        Ok(self
+            .unspent(account)?
+            .into_iter()
+            .filter(|u| !self.wallet.is_reserved(&u.dinfo.dbc.hash()))
+            .filter_map(|u| {
+                let amount = u.amount_secrets.amount();
+                u.secret_key.map(|secret_key| ReissueInput {
+                    dbc: u.dinfo.dbc.clone(),
+                    secret_key,
+                    amount,
+                })
+            })
+            .collect())
+    }
+
+    /// Spends inputs to outputs in a single transaction.  Any change is
+    /// returned to a new key of account.
+    ///
+    /// Each output Dbc is printed for its recipient, and recorded in the
+    /// wallet along with the transaction.
+    async fn reissue(
+        &mut self,
+        inputs: Vec<ReissueInput>,
+        outputs: Vec<ReissueOutput>,
+        account: &str,
+        notes: String,
+    ) -> Result<()> {
+        if self.wallet.pending.is_some() {
+            return Err(miette!(
+                "A reissue is already pending.  Use 'pending resume' or 'pending rollback' first."
+            ));
+        }
 
-        let unspent = self.unspent()?;
+        let mut rng = rng::thread_rng();
         let mut tx_builder = TransactionBuilder::default();
 
-        let mut inputs_hash: BTreeMap<KeyImage, [u8; 32]> = Default::default();
+        // public keys that may not be used as decoys: each input's own, and
+        // then those of decoys already used by another input.
+        let mut used_keys: BTreeSet<[u8; 48]> = Default::default();
+        for input in inputs.iter() {
+            let public_key = input.dbc.public_key(&input.secret_key).into_diagnostic()?;
+            used_keys.insert(public_key.to_bytes());
+        }
 
-        for (dinfo, secret_key, _amount_secrets, _id, _ownership) in unspent.iter() {
-            inputs_hash.insert(
-                dinfo.dbc.key_image(secret_key).into_diagnostic()?,
-                dinfo.dbc.hash(),
-            );
+        for input in inputs.iter() {
+            let decoys = self.decoys_for_input(&mut used_keys).await?;
             tx_builder = tx_builder
-                .add_input_dbc(&dinfo.dbc, secret_key, vec![], &mut rng)
+                .add_input_dbc(&input.dbc, &input.secret_key, decoys, &mut rng)
                 .into_diagnostic()?;
+        }
+        for output in outputs.into_iter() {
+            let owner_once = OwnerOnce::from_owner_base(output.owner, &mut rng);
+            tx_builder = tx_builder.add_output_by_amount(output.amount, owner_once);
+        }
 
-            if tx_builder.inputs_amount_sum() >= spend_amount {
-                break;
-            }
+        let inputs_amount = tx_builder.inputs_amount_sum();
+        let outputs_amount = tx_builder.outputs_amount_sum();
+        if outputs_amount > inputs_amount {
+            return Err(miette!(
+                "Outputs total {} exceeds inputs total {}",
+                outputs_amount,
+                inputs_amount
+            ));
         }
-        tx_builder = tx_builder.add_output_by_amount(spend_amount, recip_owner_once.clone());
 
-        if tx_builder.inputs_amount_sum() > tx_builder.outputs_amount_sum() {
-            let change = tx_builder.inputs_amount_sum() - tx_builder.outputs_amount_sum();
-            let secret_key = SecretKey::random();
-            self.wallet.addkey(secret_key.clone());
-            let change_owner_once =
+        let mut change_index = None;
+        if inputs_amount > outputs_amount {
+            let secret_key = self.wallet.new_account_key(account)?;
+            let owner_once =
                 OwnerOnce::from_owner_base(Owner::from(secret_key.public_key()), &mut rng);
-
-            tx_builder = tx_builder.add_output_by_amount(change, change_owner_once);
+            change_index = Some(owner_once.derivation_index);
+            tx_builder =
+                tx_builder.add_output_by_amount(inputs_amount - outputs_amount, owner_once);
         };
-        let mut dbc_builder = tx_builder.build(&mut rng).into_diagnostic()?;
+        let dbc_builder = tx_builder.build(&mut rng).into_diagnostic()?;
 
-        for (key_image, tx) in dbc_builder.inputs() {
-            let spent_proof_shares = self.broadcast_log_spent(key_image, tx).await?;
-            if let Some(dbc_hash) = inputs_hash.get(&key_image) {
-                self.wallet.mark_spent(dbc_hash);
-                dbc_builder = dbc_builder.add_spent_proof_shares(spent_proof_shares);
+        // written before anything is broadcast, so that after a failure the
+        // reissue can be resumed, or rolled back, with the pending command.
+        self.wallet.set_pending(Some(PendingReissue {
+            created: chrono::Utc::now(),
+            transaction: dbc_builder.transaction,
+            revealed_commitments: dbc_builder.revealed_commitments,
+            output_owner_map: dbc_builder.output_owner_map,
+            shares: Default::default(),
+            answered: Default::default(),
+            logged: false,
+            inputs: inputs.iter().map(|i| i.dbc.hash()).collect(),
+            change_index,
+            notes,
+        }));
+        self.store.commit(&mut self.wallet)?;
+
+        if self.config.offline {
+            println!("Transaction built.  Use 'tx export file=<path>' to export it for an online wallet to broadcast.");
+            return Ok(());
+        }
+        self.complete_pending().await.map_err(|e| {
+            miette!(
+                "{}\n\nThe reissue is pending.  Use 'pending resume' to retry it, or 'pending rollback' to cancel it.",
+                e
+            )
+        })
+    }
+
+    /// Logs the pending reissue's inputs as spent with the spentbook, then
+    /// builds its output Dbcs.  Each share is saved as it arrives, so on
+    /// resume an input is only sent to the nodes that have not yet logged it.
+    async fn complete_pending(&mut self) -> Result<()> {
+        let pending = self
+            .wallet
+            .pending
+            .clone()
+            .ok_or_else(|| miette!("No reissue is pending"))?;
+        let nodes: Vec<(XorName, SocketAddr)> = self
+            .spentbook_nodes
+            .iter()
+            .map(|(xorname, addr)| (*xorname, *addr))
+            .collect();
+
+        for (key_image, tx) in pending.dbc_builder().inputs().into_iter() {
+            let msg = wire::spentbook::wallet::request::Msg::LogSpent(key_image, tx);
+            for (xorname, addr) in nodes.iter() {
+                let answered = pending
+                    .answered
+                    .get(&key_image)
+                    .map_or(false, |nodes| nodes.contains(xorname));
+                if answered {
+                    continue;
+                }
+                let share = self.log_spent_with(msg.clone(), addr).await?;
+                self.wallet.add_pending_share(key_image, *xorname, share);
+                self.store.commit(&mut self.wallet)?;
             }
         }
+        self.finish_pending()
+    }
 
+    /// Builds the output Dbcs of the pending reissue, once every input is
+    /// logged as spent, and completes it.
+    fn finish_pending(&mut self) -> Result<()> {
+        let pending = self
+            .wallet
+            .pending
+            .clone()
+            .ok_or_else(|| miette!("No reissue is pending"))?;
+        let mut dbc_builder = pending.dbc_builder();
+        let inputs = dbc_builder.inputs();
+        if inputs
+            .iter()
+            .any(|(k, _)| pending.shares.get(k).map_or(true, Vec::is_empty))
+        {
+            return Err(miette!(
+                "Only {} of {} inputs are logged as spent",
+                pending.shares.len(),
+                inputs.len()
+            ));
+        }
+        for shares in pending.shares.values() {
+            dbc_builder = dbc_builder.add_spent_proof_shares(shares.clone());
+        }
         let dbcs = dbc_builder
             .build(&self.gen_key_manager()?)
             .into_diagnostic()?;
 
-        let mut iter = dbcs.into_iter();
-        if let Some(dbc_info) = iter.next() {
-            let (recip_dbc, _owner_once, _amount_secrets) = dbc_info;
-            let recip_dbc_hex = encode(&bincode::serialize(&recip_dbc).into_diagnostic()?);
-            let recip_dbc_is_bearer = recip_dbc.is_bearer();
-            self.wallet.add_dbc(recip_dbc, None, false)?;
-            println!("\n-- Begin DBC --\n{}\n-- End Dbc--\n", recip_dbc_hex);
-            if recip_dbc_is_bearer {
-                println!("note: this DBC is bearer and has been deposited to our wallet");
-            } else if self
-                .wallet
-                .keys
-                .contains_key(&recip_owner_once.owner_base.public_key())
-            {
-                println!("note: this DBC is 'mine' and has been deposited to our wallet");
+        // only now that the outputs exist are the inputs marked spent.
+        for (key_image, _tx) in inputs.iter() {
+            if let Some(dbc_hash) = self.wallet.dbc_hash_by_key_image(key_image) {
+                self.wallet.mark_spent(&dbc_hash);
+            }
+        }
+
+        let mut record = TxRecord::new(
+            TxKind::Reissue,
+            pending.inputs.clone(),
+            pending.notes.clone(),
+        );
+        for (dbc, owner_once, amount_secrets) in dbcs.into_iter() {
+            let change = pending.change_index == Some(owner_once.derivation_index);
+            record
+                .outputs
+                .push(TxOutput::new(&dbc, amount_secrets.amount(), change));
+
+            if change {
+                self.wallet
+                    .add_dbc(dbc, Some("change".to_string()), false)?;
+                println!("note: change DBC deposited to our wallet.");
             } else {
-                println!("note: this DBC is owned by a third party");
+                self.deliver_output(dbc, amount_secrets, &pending.notes)?;
             }
         }
+        self.wallet.record_tx(record);
+        self.wallet.set_pending(None);
 
-        if let Some(dbc_info) = iter.next() {
-            let (change_dbc, _owner_once, _amount_secrets) = dbc_info;
-            self.wallet
-                .add_dbc(change_dbc, Some("change".to_string()), false)?;
-            println!("note: change DBC deposited to our wallet.");
+        Ok(())
+    }
+
+    fn cli_pending_show(&self) -> Result<()> {
+        let pending = match self.wallet.pending.as_ref() {
+            Some(pending) => pending,
+            None => {
+                println!("No reissue is pending.");
+                return Ok(());
+            }
+        };
+        let amount: Amount = pending
+            .inputs
+            .iter()
+            .filter_map(|h| self.wallet.dbcs.get(h))
+            .filter_map(|d| d.amount_secrets.as_ref())
+            .map(|s| s.amount())
+            .sum();
+        println!("  -- Pending Reissue -- ");
+        println!("created: {}", pending.created.to_rfc3339());
+        println!("notes: {}", pending.notes);
+        println!(
+            "inputs: {}, amount: {}, logged as spent: {} of {}",
+            pending.inputs.len(),
+            amount,
+            pending.shares.len(),
+            pending.inputs.len()
+        );
+        for dbc_hash in pending.inputs.iter() {
+            println!("  {}", encode(dbc_hash));
+        }
+        Ok(())
+    }
+
+    async fn cli_pending<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+        match args.next() {
+            None | Some("show") => self.cli_pending_show(),
+            Some("resume") => self.complete_pending().await,
+            Some("rollback") => {
+                let pending = self
+                    .wallet
+                    .pending
+                    .as_ref()
+                    .ok_or_else(|| miette!("No reissue is pending"))?;
+                if pending.logged || !pending.shares.is_empty() {
+                    return Err(miette!(
+                        "A spentbook node has logged inputs of this reissue as spent, so they can not be spent again.  Use 'pending resume' instead."
+                    ));
+                }
+                println!(
+                    "Warning: if a spentbook node logged an input as spent but its reply was lost,"
+                );
+                println!("that input can no longer be spent by any other transaction.");
+                if readline_prompt("Roll back the pending reissue? [y/n]: ")? == "y" {
+                    self.wallet.set_pending(None);
+                    println!("Pending reissue rolled back.  Its inputs are available again.");
+                }
+                Ok(())
+            }
+            _ => Err(miette!("Usage: pending [show | resume | rollback]")),
+        }
+    }
+
+    /// Checks spentbook_pks against the pinned spentbook key, or if none is
+    /// pinned yet, asks the user to confirm it and pins it.
+    fn check_spentbook_pks(&mut self, spentbook_pks: &PublicKeySet) -> Result<()> {
+        match self.wallet.pinned_spentbook_pks.as_ref() {
+            Some(pinned) if pinned == spentbook_pks => Ok(()),
+            Some(_) => Err(miette!(
+                "The spentbook key differs from the pinned spentbook key"
+            )),
+            None => {
+                println!(
+                    "Spentbook public key: {}",
+                    encode(spentbook_pks.public_key().to_bytes())
+                );
+                println!(
+                    "Check that it matches the key shown by 'tx decoys' on the online wallet."
+                );
+                if readline_prompt("Trust this spentbook key? [y/n]: ")? != "y" {
+                    return Err(miette!("Spentbook key not trusted"));
+                }
+                self.wallet.pin_spentbook_pks(spentbook_pks.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Moves a reissue between an offline wallet, which holds the keys, and
+    /// an online wallet, which talks to the spentbook.
+    async fn cli_tx<'a>(&mut self, mut args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let subcmd = args.next();
+        let kv = parse_kv_args(args, &["file", "out", "count"])?;
+        let file = || {
+            kv.get("file")
+                .map(PathBuf::from)
+                .ok_or_else(|| miette!("Missing argument file=<path>"))
+        };
+
+        match subcmd {
+            // online: export decoys for the offline wallet to build rings with.
+            Some("decoys") => {
+                let count = match kv.get("count") {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| miette!("Invalid count '{}'", count))?,
+                    None => OFFLINE_DECOYS,
+                };
+                let spentbook_pks = self
+                    .spentbook_pks
+                    .clone()
+                    .ok_or_else(|| miette!("spentbook_pks not available"))?;
+                let decoys = self.request_decoys(count).await?;
+                println!(
+                    "Spentbook public key: {}",
+                    encode(spentbook_pks.public_key().to_bytes())
+                );
+                let num_decoys = decoys.len();
+                write_hex_file(
+                    &file()?,
+                    &OfflineDecoys {
+                        spentbook_pks,
+                        decoys,
+                    },
+                )?;
+                println!("Exported {} decoys.", num_decoys);
+            }
+            // offline: load decoys exported by an online wallet.  The first
+            // time, the spentbook key is pinned once the user confirms it.
+            Some("load_decoys") => {
+                let offline_decoys: OfflineDecoys = read_hex_file(&file()?)?;
+                self.check_spentbook_pks(&offline_decoys.spentbook_pks)?;
+                println!("Loaded {} decoys.", offline_decoys.decoys.len());
+                self.decoys = offline_decoys.decoys;
+            }
+            // offline: export the pending reissue's transaction.
+            Some("export") => {
+                let pending = self
+                    .wallet
+                    .pending
+                    .as_ref()
+                    .ok_or_else(|| miette!("No reissue is pending"))?;
+                if self.wallet.pinned_spentbook_pks.is_none() {
+                    return Err(miette!("No spentbook key is pinned, so spent proofs could not be checked.  Load decoys from an online wallet with 'tx load_decoys' first."));
+                }
+                let offline_tx = OfflineTx {
+                    transaction: pending.transaction.clone(),
+                    key_images: pending
+                        .dbc_builder()
+                        .inputs()
+                        .into_iter()
+                        .map(|(k, _)| k)
+                        .collect(),
+                };
+                write_hex_file(&file()?, &offline_tx)?;
+                // it may be broadcast from now on, so can not be rolled back.
+                self.wallet.set_pending_logged();
+                println!("Exported transaction.  Broadcast it from an online wallet with 'tx broadcast'.");
+            }
+            // online: log an offline wallet's transaction as spent.
+            Some("broadcast") => {
+                let out = kv
+                    .get("out")
+                    .map(PathBuf::from)
+                    .ok_or_else(|| miette!("Missing argument out=<path>"))?;
+                let offline_tx: OfflineTx = read_hex_file(&file()?)?;
+                let spentbook_pks = self
+                    .spentbook_pks
+                    .clone()
+                    .ok_or_else(|| miette!("spentbook_pks not available"))?;
+
+                let mut shares: BTreeMap<KeyImage, Vec<SpentProofShare>> = Default::default();
+                for key_image in offline_tx.key_images.into_iter() {
+                    let spent_proof_shares = self
+                        .broadcast_log_spent(key_image, offline_tx.transaction.clone())
+                        .await?;
+                    shares.insert(key_image, spent_proof_shares);
+                }
+                write_hex_file(
+                    &out,
+                    &OfflineProofs {
+                        spentbook_pks,
+                        shares,
+                    },
+                )?;
+                println!("Transaction logged as spent.  Finish it on the offline wallet with 'tx finalize'.");
+            }
+            // offline: build the output Dbcs from the spent proofs.
+            Some("finalize") => {
+                let proofs: OfflineProofs = read_hex_file(&file()?)?;
+                let pinned = self
+                    .wallet
+                    .pinned_spentbook_pks
+                    .clone()
+                    .ok_or_else(|| miette!("No spentbook key is pinned"))?;
+                // a proofs file signed by any other key is not from the spentbook.
+                let signed_by_pinned = proofs.spentbook_pks == pinned
+                    && proofs
+                        .shares
+                        .values()
+                        .flatten()
+                        .all(|share| *share.spentbook_pks() == pinned);
+                if !signed_by_pinned {
+                    return Err(miette!(
+                        "The spent proofs are not signed with the pinned spentbook key"
+                    ));
+                }
+
+                let pending = self
+                    .wallet
+                    .pending
+                    .as_ref()
+                    .ok_or_else(|| miette!("No reissue is pending"))?;
+                let mut shares = Vec::new();
+                for (key_image, _tx) in pending.dbc_builder().inputs().into_iter() {
+                    let input_shares = proofs
+                        .shares
+                        .get(&key_image)
+                        .ok_or_else(|| miette!("The file has no spent proofs for an input of the pending reissue"))?
+                        .clone();
+                    shares.push((key_image, input_shares));
+                }
+                for (key_image, input_shares) in shares.into_iter() {
+                    self.wallet.add_pending_shares(key_image, input_shares);
+                }
+                self.spentbook_pks = Some(pinned);
+                self.finish_pending()?;
+            }
+            _ => {
+                return Err(miette!(
+                    "Usage: tx [decoys file=<path> count=<n> | load_decoys file=<path> | export file=<path> | broadcast file=<path> out=<path> | finalize file=<path>]"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a reissued Dbc to our wallet and prints it for its recipient.
+    fn deliver_output(
+        &mut self,
+        dbc: Dbc,
+        amount_secrets: AmountSecrets,
+        notes: &str,
+    ) -> Result<()> {
+        let dbc_hex = encode(&bincode::serialize(&dbc).into_diagnostic()?);
+        let is_bearer = dbc.is_bearer();
+        let is_mine = self
+            .wallet
+            .keys
+            .contains_key(&dbc.owner_base().public_key());
+        let n = if notes.is_empty() {
+            None
+        } else {
+            Some(notes.to_string())
+        };
+        let recipient = if is_bearer {
+            "bearer".to_string()
+        } else {
+            let owner = dbc.owner_base().public_key();
+            match self.wallet.contact_alias(&owner) {
+                Some(alias) => alias.to_string(),
+                None => encode(&owner.to_bytes()),
+            }
+        };
+        println!(
+            "\nrecipient: {}, amount: {}",
+            recipient,
+            amount_secrets.amount()
+        );
+        let dinfo = self.wallet.add_dbc(dbc, n, false)?;
+
+        // a Dbc owned by one of our keys need not be handed to anyone.
+        if is_mine {
+            println!("note: this DBC is 'mine' and has been deposited to our wallet");
+            return Ok(());
         }
 
+        println!("\n-- Begin DBC --\n{}\n-- End Dbc--\n", dbc_hex);
+        if is_bearer {
+            println!("note: this DBC is bearer and has been deposited to our wallet");
+        } else {
+            let secrets_hex = encode(&bincode::serialize(&amount_secrets).into_diagnostic()?);
+            println!(
+                "-- Begin AmountSecrets --\n{}\n-- End AmountSecrets --\n",
+                secrets_hex
+            );
+            println!("note: this DBC is owned by a third party");
+            println!("note: the AmountSecrets are only needed by a watch-only wallet");
+            if dinfo.ownership(&self.wallet) == Ownership::WatchOnly {
+                self.wallet
+                    .set_amount_secrets(&dinfo.dbc.hash(), amount_secrets);
+            }
+        }
         Ok(())
     }
 
@@ -490,41 +3610,14 @@ impl WalletNodeClient {
         }
     }
 
-    /*
-        fn cli_reissue_manual(&mut self) -> Result<()> {
-
-            let balance = self.balance()?;
-            if balance == 0 {
-                println!("No funds available for reissue.");
-                return Ok(());
-            }
-
-            println!("Available balance: {}", balance);
-
-            loop {
-                let amount = readline_prompt("Amount to spend: ");
-                if amount <= balance {
-                    break;
-                }
-                println!("  entered amount exceeds available balance of {}.\n", balance);
-            }
-
-            println!("  -- Unspent Dbcs -- ");
-            let unspent = self.unspent()?;
-            for ((idx, (dinfo, amount, id, ownership)) in unspent.iter().enumerate() {
-                println!("{}. {} --> amount: {} ({})", idx, id, amount, ownership);
-            }
-
-            println!("\nchoose input ");
-        }
-    */
-
     // todo: move into Wallet
-    fn balance(&self) -> Result<Amount> {
+    /// Returns the spendable balance.  Watch-only Dbcs are excluded.
+    fn balance(&self, account: Option<&str>) -> Result<Amount> {
         Ok(self
-            .unspent()?
+            .unspent(account)?
             .iter()
-            .map(|(_, _, amount_secrets, ..)| amount_secrets.amount())
+            .filter(|u| u.secret_key.is_some())
+            .map(|u| u.amount_secrets.amount())
             .sum())
     }
 
@@ -549,66 +3642,394 @@ impl WalletNodeClient {
             dbc_builder = dbc_builder.add_spent_proof_shares(spent_proof_shares);
         }
 
+        let mut record = TxRecord::new(TxKind::Genesis, vec![], "Genesis Dbc".to_string());
         for dbc_info in dbc_builder
             .build(&self.gen_key_manager()?)
             .into_diagnostic()?
             .into_iter()
         {
-            let (genesis_dbc, _owner_once, _amount_secrets) = dbc_info;
+            let (genesis_dbc, _owner_once, amount_secrets) = dbc_info;
+            record
+                .outputs
+                .push(TxOutput::new(&genesis_dbc, amount_secrets.amount(), false));
             self.wallet
                 .add_dbc(genesis_dbc, Some("Genesis Dbc".to_string()), false)?;
         }
+        self.wallet.record_tx(record);
+
+        Ok(())
+    }
+
+    fn cli_keys(&self) -> Result<()> {
+        println!("  -- Wallet Keys -- ");
+        for (pk, _sk) in self.wallet.keys.iter() {
+            println!("  {}", encode(&pk.to_bytes()));
+        }
+        Ok(())
+    }
+
+    /// Lists the transaction history, or with an index argument,
+    /// shows the inputs and outputs of that transaction.
+    fn cli_history(&self, index: Option<&str>) -> Result<()> {
+        let index = match index {
+            Some(i) => i.parse::<usize>().into_diagnostic()?,
+            None => {
+                println!("  -- Transaction History -- ");
+                for (idx, r) in self.wallet.history.iter().enumerate() {
+                    println!(
+                        "{}. {}, {}, inputs: {}, outputs: {}, sent: {}, notes: {}",
+                        idx,
+                        r.time.to_rfc3339(),
+                        r.kind,
+                        r.inputs.len(),
+                        r.outputs.len(),
+                        r.amount_sent(),
+                        r.notes
+                    );
+                }
+                return Ok(());
+            }
+        };
+
+        let r = self
+            .wallet
+            .history
+            .get(index)
+            .ok_or_else(|| miette!("No transaction at index {}", index))?;
+
+        println!("  -- Transaction {} -- ", index);
+        println!("time:  {}", r.time.to_rfc3339());
+        println!("kind:  {}", r.kind);
+        println!("notes: {}", r.notes);
+        println!("inputs:");
+        for dbc_hash in r.inputs.iter() {
+            println!("  {}", encode(dbc_hash));
+        }
+        println!("outputs:");
+        for o in r.outputs.iter() {
+            let recipient = match o.recipient.as_ref() {
+                Some(pk) => match self.wallet.contact_alias(pk) {
+                    Some(alias) => format!("{} ({})", encode(&pk.to_bytes()), alias),
+                    None => encode(&pk.to_bytes()),
+                },
+                None => "bearer".to_string(),
+            };
+            println!(
+                "  {}, amount: {}, recipient: {}{}",
+                encode(o.dbc_hash),
+                o.amount,
+                recipient,
+                if o.change { " (change)" } else { "" }
+            );
+        }
+        Ok(())
+    }
+
+    fn cli_spent<'a>(&self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let filter = DbcFilter::parse(args)?;
+
+        println!("  -- Spent Dbcs -- ");
+        for (dbc_hash, dinfo) in self.wallet.spent().into_iter() {
+            let spent = match dinfo.spent {
+                Some(spent) => spent,
+                None => continue,
+            };
+            if !filter.matches(&self.wallet, dinfo, spent) {
+                continue;
+            }
+            let ownership = dinfo.ownership(&self.wallet);
+            let amount = match self.wallet.dbc_amount(dinfo) {
+                Some(amount) => amount.to_string(),
+                None => "unknown".to_string(),
+            };
+            println!(
+                "{}, rcvd: {}, spent: {}, amount: {} ({}), notes: {}, tags: {}",
+                encode(dbc_hash),
+                dinfo.received.to_rfc3339(),
+                spent.to_rfc3339(),
+                amount,
+                ownership,
+                dinfo.notes,
+                dinfo.tags_list()
+            );
+        }
+        Ok(())
+    }
+
+    fn cli_unspent<'a>(&self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let filter = DbcFilter::parse_unspent(args)?;
+
+        println!("  -- Unspent Dbcs -- ");
+        for u in self.unspent(None)?.iter() {
+            if !filter.matches(&self.wallet, u.dinfo, u.dinfo.received) {
+                continue;
+            }
+            println!(
+                "{}, rcvd: {}, amount: {} ({}), account: {}, notes: {}, tags: {}",
+                u.id,
+                u.dinfo.received.to_rfc3339(),
+                u.amount_secrets.amount(),
+                u.ownership,
+                self.wallet
+                    .account_of(&u.dinfo.dbc.owner_base().public_key()),
+                u.dinfo.notes,
+                u.dinfo.tags_list()
+            );
+        }
+        Ok(())
+    }
+
+    /// Lists every Dbc in the wallet, spent or not, that matches the filter.
+    fn cli_search<'a>(&self, args: impl Iterator<Item = &'a str>) -> Result<()> {
+        let filter = DbcFilter::parse(args)?;
+
+        println!("  -- Matching Dbcs -- ");
+        let mut matches: Vec<(&[u8; 32], &DbcInfo)> = self
+            .wallet
+            .dbcs
+            .iter()
+            .filter(|(_, dinfo)| filter.matches(&self.wallet, dinfo, dinfo.received))
+            .collect();
+        matches.sort_by_key(|(_, dinfo)| dinfo.received);
+
+        for (dbc_hash, dinfo) in matches.into_iter() {
+            let amount = match self.wallet.dbc_amount(dinfo) {
+                Some(amount) => amount.to_string(),
+                None => "unknown".to_string(),
+            };
+            println!(
+                "{}, rcvd: {}, amount: {} ({}), {}, notes: {}, tags: {}",
+                encode(dbc_hash),
+                dinfo.received.to_rfc3339(),
+                amount,
+                dinfo.ownership(&self.wallet),
+                if dinfo.spent.is_some() {
+                    "spent"
+                } else {
+                    "unspent"
+                },
+                dinfo.notes,
+                dinfo.tags_list()
+            );
+        }
+        Ok(())
+    }
+
+    fn cli_strategy(&mut self, strategy: Option<&str>) -> Result<()> {
+        if let Some(strategy) = strategy {
+            self.wallet.set_strategy(strategy.parse()?);
+        }
+        println!("Coin selection strategy: {}", self.wallet.strategy);
+        Ok(())
+    }
+
+    /// Prints the contents of a pasted Dbc, PublicKey, RingCtTransaction
+    /// or SpentProofShare.  The type is detected from the bytes.
+    fn cli_decode(&self) -> Result<()> {
+        let bytes = decode(readline_prompt_nl("Paste hex: ")?)?;
+
+        // a PublicKey is pasted as its raw bytes, not bincode.
+        if let Ok(pk_bytes) = <[u8; 48]>::try_from(bytes.as_slice()) {
+            if let Ok(public_key) = PublicKey::from_bytes(pk_bytes) {
+                self.print_public_key(&public_key);
+                return Ok(());
+            }
+        }
+        if let Ok(dbc) = from_bytes_exact::<Dbc>(&bytes) {
+            self.print_dbc(&dbc)
+        } else if let Ok(tx) = from_bytes_exact::<RingCtTransaction>(&bytes) {
+            println!("  -- RingCtTransaction -- ");
+            print_transaction(&tx);
+            Ok(())
+        } else if let Ok(share) = from_bytes_exact::<SpentProofShare>(&bytes) {
+            println!("  -- SpentProofShare -- ");
+            println!("key_image: {}", encode(share.key_image().to_bytes()));
+            println!("transaction_hash: {}", encode(share.transaction_hash()));
+            println!(
+                "spentbook public key: {}",
+                encode(share.spentbook_pks().public_key().to_bytes())
+            );
+            println!(
+                "signature share index: {}",
+                share.spentbook_sig_share().index
+            );
+            Ok(())
+        } else {
+            Err(miette!(
+                "Not a Dbc, PublicKey, RingCtTransaction or SpentProofShare"
+            ))
+        }
+    }
+
+    fn print_public_key(&self, public_key: &PublicKey) {
+        println!("  -- PublicKey -- ");
+        println!("{}", encode(public_key.to_bytes()));
+        if self.wallet.keys.contains_key(public_key) {
+            println!("ours, account: {}", self.wallet.account_of(public_key));
+        } else if self.wallet.watch_keys.contains(public_key) {
+            println!("watched");
+        } else if let Some(alias) = self.wallet.contact_alias(public_key) {
+            println!("contact: {}", alias);
+        }
+    }
+
+    fn print_dbc(&self, dbc: &Dbc) -> Result<()> {
+        println!("  -- Dbc -- ");
+        println!("hash: {}", encode(dbc.hash()));
+        println!(
+            "owner: {}",
+            encode(dbc.owner_base().public_key().to_bytes())
+        );
+        println!("bearer: {}", dbc.is_bearer());
+
+        match self.wallet.dbc_secret_key(dbc) {
+            Some(secret_key) => {
+                // a malformed Dbc is still printed, as far as it can be.
+                match dbc.key_image(&secret_key) {
+                    Ok(key_image) => println!("key_image: {}", encode(key_image.to_bytes())),
+                    Err(e) => println!("key_image: unknown ({})", e),
+                }
+                match dbc.amount_secrets(&secret_key) {
+                    Ok(amount_secrets) => println!("amount: {}", amount_secrets.amount()),
+                    Err(e) => println!("amount: unknown ({})", e),
+                }
+            }
+            None => println!("amount: unknown (we do not hold the owner's secret key)"),
+        }
+        if let Some(dinfo) = self.wallet.dbcs.get(&dbc.hash()) {
+            println!(
+                "in wallet: {}",
+                if dinfo.spent.is_some() {
+                    "spent"
+                } else {
+                    "unspent"
+                }
+            );
+        }
+
+        println!("spent proofs: {}", dbc.spent_proofs.len());
+        for spent_proof in dbc.spent_proofs.iter() {
+            println!(
+                "  key_image: {}",
+                encode(spent_proof.key_image().to_bytes())
+            );
+        }
+        print_transaction(&dbc.transaction);
+        Ok(())
+    }
+
+    /// Validates a pasted Dbc, or with a Dbc hash prefix, one of ours.
+    fn cli_validate(&self, prefix: Option<&str>) -> Result<()> {
+        let pasted: Dbc;
+        let dbc = match prefix {
+            Some(prefix) => &self.wallet.dbcs[&self.wallet.find_by_prefix(prefix)?].dbc,
+            None => {
+                pasted = from_le_hex(&readline_prompt_nl("Paste Dbc: ")?)?;
+                &pasted
+            }
+        };
 
+        self.validate_dbc(dbc)?;
+        println!("Dbc is valid.");
+        if self.wallet.dbc_secret_key(dbc).is_none() {
+            println!("note: this DBC is owned by a third party, so its amount commitment was not checked");
+        }
         Ok(())
     }
 
-    fn cli_keys(&self) -> Result<()> {
-        println!("  -- Wallet Keys -- ");
-        for (pk, _sk) in self.wallet.keys.iter() {
-            println!("  {}", encode(&pk.to_bytes()));
-        }
+    /// Verifies dbc's transaction and spent proofs against the spentbook's
+    /// key.  If we hold its secret key, its amount commitment is verified too.
+    fn validate_dbc(&self, dbc: &Dbc) -> Result<()> {
+        let key_manager = self.gen_key_manager()?;
+        let result = match self.wallet.dbc_secret_key(dbc) {
+            Some(secret_key) => dbc.verify(&secret_key, &key_manager),
+            None => TransactionVerifier::verify(&key_manager, &dbc.transaction, &dbc.spent_proofs),
+        };
+        result.map_err(|e| miette!("Invalid Dbc.  The {} check failed: {}", failed_check(&e), e))
+    }
+
+    fn cli_notes(&mut self, prefix: Option<&str>) -> Result<()> {
+        let prefix = prefix.ok_or_else(|| miette!("Usage: notes <dbc_hash_prefix>"))?;
+        let dbc_hash = self.wallet.find_by_prefix(prefix)?;
+
+        println!("Current notes: {}", self.wallet.dbcs[&dbc_hash].notes);
+        let notes = readline_prompt_default("New notes (empty to clear): ", "")?;
+        self.wallet.set_notes(&dbc_hash, notes)?;
+        println!("Notes updated for {}", encode(dbc_hash));
         Ok(())
     }
 
-    fn cli_unspent(&self) -> Result<()> {
-        println!("  -- Unspent Dbcs -- ");
-        for (dinfo, _secret_key, amount_secrets, id, ownership) in self.unspent()?.iter() {
-            println!(
-                "{}, rcvd: {}, amount: {} ({})",
-                id,
-                dinfo.received.to_rfc3339(),
-                amount_secrets.amount(),
-                ownership
-            );
+    /// Adds (or if add is false, removes) tags on a Dbc.
+    fn cli_tag<'a>(&mut self, mut args: impl Iterator<Item = &'a str>, add: bool) -> Result<()> {
+        let usage = || {
+            miette!(
+                "Usage: {} <dbc_hash_prefix> <tag>...",
+                if add { "tag" } else { "untag" }
+            )
+        };
+        let dbc_hash = self.wallet.find_by_prefix(args.next().ok_or_else(usage)?)?;
+        let tags: Vec<&str> = args.collect();
+        if tags.is_empty() {
+            return Err(usage());
+        }
+
+        for tag in tags.into_iter() {
+            let changed = if add {
+                self.wallet.add_tag(&dbc_hash, tag)?
+            } else {
+                self.wallet.remove_tag(&dbc_hash, tag)?
+            };
+            if !changed {
+                println!("  skipped '{}'", tag);
+            }
         }
+        println!(
+            "Tags for {}: {}",
+            encode(dbc_hash),
+            self.wallet.dbcs[&dbc_hash].tags_list()
+        );
         Ok(())
     }
 
-    #[allow(clippy::type_complexity)]
-    fn unspent(&self) -> Result<Vec<(&DbcInfo, SecretKey, AmountSecrets, String, Ownership)>> {
-        let mut unspents: Vec<(&DbcInfo, SecretKey, AmountSecrets, String, Ownership)> =
-            Default::default();
+    /// Returns our unspent Dbcs, optionally only those of one account.
+    /// Includes watch-only Dbcs for which we have been given the amount secrets.
+    fn unspent(&self, account: Option<&str>) -> Result<Vec<UnspentDbc>> {
+        if let Some(account) = account {
+            self.wallet.check_account(account)?;
+        }
+        let mut unspents: Vec<UnspentDbc> = Default::default();
 
         for (_key_image, dinfo) in self.wallet.unspent().into_iter() {
-            let ownership = dinfo.ownership(&self.wallet.keys);
-            let (secret_key, amount_secrets) = match ownership {
-                Ownership::Mine => {
-                    if let Some(key) = self.wallet.keys.get(&dinfo.dbc.owner_base().public_key()) {
-                        let sk = key.inner().clone();
-                        let secrets = dinfo.dbc.amount_secrets(&sk).into_diagnostic()?;
-                        (sk, secrets)
-                    } else {
-                        return Err(miette!("Secret key not found"));
-                    }
+            if let Some(account) = account {
+                if self.wallet.account_of(&dinfo.dbc.owner_base().public_key()) != account {
+                    continue;
                 }
-                Ownership::NotMine => continue,
-                Ownership::Bearer => (
-                    dinfo.dbc.owner_base().secret_key().into_diagnostic()?,
-                    dinfo.dbc.amount_secrets_bearer().into_diagnostic()?,
+            }
+            let ownership = dinfo.ownership(&self.wallet);
+            let secret_key = match ownership {
+                Ownership::Mine | Ownership::Bearer => Some(
+                    self.wallet
+                        .secret_key_of(dinfo)
+                        .ok_or_else(|| miette!("Secret key not found"))?,
                 ),
+                Ownership::NotMine => continue,
+                Ownership::WatchOnly => None,
+            };
+            // cached when the Dbc was added, so no decryption is needed here.
+            let amount_secrets = match dinfo.amount_secrets.as_ref() {
+                Some(secrets) => secrets.clone(),
+                None if secret_key.is_some() => {
+                    return Err(miette!("Amount secrets not found for Dbc"));
+                }
+                None => continue,
             };
-            let id = encode(dinfo.dbc.hash());
-            unspents.push((dinfo, secret_key, amount_secrets, id, ownership));
+            unspents.push(UnspentDbc {
+                dinfo,
+                secret_key,
+                amount_secrets,
+                id: encode(dinfo.dbc.hash()),
+                ownership,
+            });
         }
         Ok(unspents)
     }
@@ -646,23 +4067,93 @@ impl WalletNodeClient {
         let mut shares: Vec<SpentProofShare> = Default::default();
 
         for (_xorname, addr) in self.spentbook_nodes.iter() {
-            let reply_msg = self.send_spentbook_network_msg(msg.clone(), addr).await?;
-            let share = match reply_msg {
-                wire::spentbook::wallet::reply::Msg::LogSpent(share_result) => {
-                    share_result.into_diagnostic()?
-                }
-                _ => return Err(miette!("got unexpected reply from spentbook node")),
-            };
-            shares.push(share);
+            shares.push(self.log_spent_with(msg.clone(), addr).await?);
         }
         Ok(shares)
     }
 
+    /// Sends a LogSpent request to one spentbook node, returning its share.
+    async fn log_spent_with(
+        &self,
+        msg: wire::spentbook::wallet::request::Msg,
+        addr: &SocketAddr,
+    ) -> Result<SpentProofShare> {
+        match self.send_spentbook_network_msg(msg, addr).await? {
+            wire::spentbook::wallet::reply::Msg::LogSpent(share_result) => {
+                share_result.into_diagnostic()
+            }
+            _ => Err(miette!("got unexpected reply from spentbook node")),
+        }
+    }
+
+    /// Returns up to ring_size - 1 decoys for an input, skipping any whose
+    /// public key is in used_keys.  The spentbook log includes our own
+    /// outputs, so an input could otherwise be returned as its own decoy.
+    async fn decoys_for_input(
+        &self,
+        used_keys: &mut BTreeSet<[u8; 48]>,
+    ) -> Result<Vec<DecoyInput>> {
+        let count = self.config.ring_size.saturating_sub(1);
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        // asks for extra, as some may be skipped.
+        Ok(self
+            .request_decoys(count + used_keys.len())
+            .await?
+            .into_iter()
+            .filter(|decoy| used_keys.insert(decoy.public_key().to_compressed()))
+            .take(count)
+            .collect())
+    }
+
+    /// Requests up to count random decoys from the spentbook, to hide an
+    /// input among.  Nodes are tried in turn until one answers.
+    async fn request_decoys(&self, count: usize) -> Result<Vec<DecoyInput>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        if self.config.offline {
+            if self.decoys.is_empty() {
+                return Err(miette!(
+                    "No decoys loaded.  Use 'tx load_decoys file=<path>', or --ring-size 1"
+                ));
+            }
+            return Ok(self
+                .decoys
+                .choose_multiple(&mut rng::thread_rng(), count)
+                .cloned()
+                .collect());
+        }
+        let msg = wire::spentbook::wallet::request::Msg::Decoys(count);
+
+        for (_xorname, addr) in self.spentbook_nodes.iter() {
+            match self.send_spentbook_network_msg(msg.clone(), addr).await {
+                Ok(wire::spentbook::wallet::reply::Msg::Decoys(Ok(decoys))) => {
+                    if decoys.len() < count {
+                        debug!(
+                            "spentbook returned only {} of {} decoys requested",
+                            decoys.len(),
+                            count
+                        );
+                    }
+                    return Ok(decoys);
+                }
+                Ok(reply) => debug!("unexpected decoys reply from {}: {:?}", addr, reply),
+                Err(e) => debug!("decoys request to {} failed: {}", addr, e),
+            }
+        }
+        Err(miette!("Unable to obtain decoys from any spentbook node"))
+    }
+
     async fn send_spentbook_network_msg(
         &self,
         msg: wire::spentbook::wallet::request::Msg,
         dest_addr: &SocketAddr,
     ) -> Result<wire::spentbook::wallet::reply::Msg> {
+        if self.config.offline {
+            return Err(miette!("This wallet is offline"));
+        }
         debug!("Sending message to {:?} --> {:#?}", dest_addr, msg);
 
         let m = wire::spentbook::Msg::Wallet(wire::spentbook::wallet::Msg::Request(msg));
@@ -748,6 +4239,46 @@ fn readline_prompt_default(prompt: &str, default: &str) -> Result<String> {
     }
 }
 
+/// Prompts for a passphrase and reads it without echoing to the terminal.
+/// Re-prompts in a loop if input is empty.
+fn readline_password(prompt: &str) -> Result<String> {
+    use std::io::Write;
+    loop {
+        print!("{}", prompt);
+        std::io::stdout().flush().into_diagnostic()?;
+
+        #[cfg(unix)]
+        let line = {
+            let tty_fd = std::io::stdin().as_raw_fd();
+            let termios_old = Termios::from_fd(tty_fd).into_diagnostic()?;
+            let mut termios_new = termios_old;
+            termios_new.c_lflag &= !ECHO;
+            tcsetattr(tty_fd, TCSADRAIN, &termios_new).into_diagnostic()?;
+            let line = readline();
+            tcsetattr(tty_fd, TCSADRAIN, &termios_old).into_diagnostic()?;
+            println!();
+            line?
+        };
+        #[cfg(not(unix))]
+        let line = readline()?;
+
+        if !line.is_empty() {
+            return Ok(line);
+        }
+    }
+}
+
+/// Prompts for a new passphrase twice, until both entries match.
+fn readline_new_password() -> Result<String> {
+    loop {
+        let passphrase = readline_password("New passphrase: ")?;
+        if passphrase == readline_password("Confirm passphrase: ")? {
+            return Ok(passphrase);
+        }
+        println!("Passphrases do not match.\n");
+    }
+}
+
 /// Reads stdin to end of line, and strips newline
 fn readline() -> Result<String> {
     let mut line = String::new();
@@ -755,6 +4286,203 @@ fn readline() -> Result<String> {
     Ok(line.trim().to_string())
 }
 
+fn print_transaction(tx: &RingCtTransaction) {
+    println!("transaction hash: {}", encode(tx.hash()));
+    println!("inputs: {}", tx.mlsags.len());
+    for (idx, mlsag) in tx.mlsags.iter().enumerate() {
+        println!("  {}. ring size: {}", idx, mlsag.ring.len());
+    }
+    println!("outputs: {}", tx.outputs.len());
+}
+
+/// Returns true if secrets open the amount commitment of one of dbc's
+/// transaction outputs.  Without the owner's secret key, which a watch-only
+/// wallet lacks, the Dbc's own output can not be told apart from the others.
+fn amount_secrets_match(dbc: &Dbc, secrets: &AmountSecrets) -> bool {
+    let revealed = RevealedCommitment {
+        value: secrets.amount(),
+        blinding: secrets.blinding_factor(),
+    };
+    let commitment = G1Affine::from(revealed.commit(&Default::default()));
+    dbc.transaction
+        .outputs
+        .iter()
+        .any(|output| output.commitment() == commitment)
+}
+
+/// Names the check of a Dbc's validation that failed with e.
+fn failed_check(e: &sn_dbc::Error) -> &'static str {
+    use sn_dbc::Error;
+    match e {
+        Error::SpentProofInputLenMismatch
+        | Error::SpentProofInputKeyImageMismatch
+        | Error::InvalidSpentProofSignature(..)
+        | Error::FailedSignature
+        | Error::UnrecognisedAuthority => "spent proofs",
+        Error::RingCt(_) | Error::TransactionMustHaveAnInput => "transaction",
+        Error::AmountCommitmentsDoNotMatch => "amount commitment",
+        Error::DecryptionBySecretKeyFailed => "ownership",
+        _ => "Dbc",
+    }
+}
+
+/// Parses `key=value` command arguments.  Keys not in allowed are rejected.
+fn parse_kv_args<'a>(
+    args: impl Iterator<Item = &'a str>,
+    allowed: &[&str],
+) -> Result<BTreeMap<&'a str, &'a str>> {
+    let mut kv: BTreeMap<&'a str, &'a str> = Default::default();
+    for arg in args {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| miette!("Invalid argument '{}'.  Expected key=value", arg))?;
+        if !allowed.contains(&key) {
+            return Err(miette!(
+                "Unknown argument '{}'.  Expected one of: {}",
+                key,
+                allowed.join(", ")
+            ));
+        }
+        kv.insert(key, value);
+    }
+    Ok(kv)
+}
+
+/// Parses a list of indexes separated by commas or spaces, eg "0,2 3".
+/// Each must be less than len.  Duplicates are ignored.
+fn parse_indexes(list: &str, len: usize) -> Result<Vec<usize>> {
+    let mut indexes = BTreeSet::new();
+    for idx in list
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+    {
+        let idx: usize = idx
+            .parse()
+            .map_err(|_| miette!("Invalid index '{}'", idx))?;
+        if idx >= len {
+            return Err(miette!("Index {} is out of range", idx));
+        }
+        indexes.insert(idx);
+    }
+    if indexes.is_empty() {
+        return Err(miette!("No indexes given"));
+    }
+    Ok(indexes.into_iter().collect())
+}
+
+/// Returns the powers of two that sum to amount, largest first.
+fn power_of_two_denominations(amount: Amount) -> Vec<Amount> {
+    (0..Amount::BITS)
+        .rev()
+        .map(|bit| 1 << bit)
+        .filter(|denomination| amount & denomination != 0)
+        .collect()
+}
+
+/// Parses a list of denominations such as "100x3,50,10x5", where xN
+/// repeats a denomination N times.  At most MAX_OUTPUTS are allowed.
+fn parse_denominations(list: &str) -> Result<Vec<Amount>> {
+    let mut denominations = Vec::new();
+    for item in list.split(',').filter(|s| !s.is_empty()) {
+        let (denomination, count) = match item.split_once('x') {
+            Some((denomination, count)) => (denomination, count),
+            None => (item, "1"),
+        };
+        let denomination: Amount = denomination
+            .parse()
+            .map_err(|_| miette!("Invalid denomination '{}'", item))?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| miette!("Invalid count in '{}'", item))?;
+        if denomination == 0 {
+            return Err(miette!("Denominations must be greater than zero"));
+        }
+        if count == 0 {
+            return Err(miette!("Counts must be greater than zero"));
+        }
+        if count > MAX_OUTPUTS - denominations.len().min(MAX_OUTPUTS) {
+            return Err(miette!("At most {} denominations are allowed", MAX_OUTPUTS));
+        }
+        denominations.extend(std::iter::repeat(denomination).take(count));
+    }
+    denominations_total(&denominations)?;
+    Ok(denominations)
+}
+
+/// Returns the sum of denominations, or an error if it overflows.
+fn denominations_total(denominations: &[Amount]) -> Result<Amount> {
+    denominations.iter().try_fold(0, |total: Amount, d| {
+        total
+            .checked_add(*d)
+            .ok_or_else(|| miette!("Denominations total overflows"))
+    })
+}
+
+/// Parses the optional `account=<name>` argument of a command.
+fn account_arg<'a>(args: impl Iterator<Item = &'a str>) -> Result<Option<&'a str>> {
+    Ok(parse_kv_args(args, &["account"])?.remove("account"))
+}
+
+/// Parses a hex encoded PublicKey
+fn public_key_from_hex(hex: &str) -> Result<PublicKey> {
+    let mut bytes = [0u8; 48];
+    let d = decode(hex)?;
+    if d.len() != bytes.len() {
+        return Err(miette!("A public key must be {} bytes", bytes.len()));
+    }
+    bytes.copy_from_slice(&d);
+    PublicKey::from_bytes(bytes).into_diagnostic()
+}
+
+/// Parses a YYYY-MM-DD date as midnight UTC
+fn parse_date(s: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").into_diagnostic()?;
+    Ok(chrono::DateTime::from_utc(
+        date.and_hms(0, 0, 0),
+        chrono::Utc,
+    ))
+}
+
+/// Returns path with ".{suffix}" appended, eg .wallet.dat.1
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".");
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+/// Shifts path.1 -> path.2 ... dropping the oldest, then copies path to path.1
+fn rotate_backups(path: &Path, backups: usize) -> Result<()> {
+    if backups == 0 || !path.exists() {
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        let from = suffixed_path(path, &n.to_string());
+        if from.exists() {
+            std::fs::rename(&from, suffixed_path(path, &(n + 1).to_string())).into_diagnostic()?;
+        }
+    }
+    std::fs::copy(path, suffixed_path(path, "1")).into_diagnostic()?;
+    Ok(())
+}
+
+/// Syncs the directory containing path, so that a rename into it is durable.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .into_diagnostic()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
 /// Hex encode bytes
 fn encode<T: AsRef<[u8]>>(data: T) -> String {
     hex::encode(data)
@@ -769,6 +4497,31 @@ fn from_le_hex<T: for<'de> Deserialize<'de>>(s: &str) -> Result<T> {
     bincode::deserialize(&decode(s)?).into_diagnostic()
 }
 
+/// Deserializes bincode bytes that must hold exactly one T, so that decode
+/// can tell the types apart.
+fn from_bytes_exact<T: for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T> {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(bytes)
+        .into_diagnostic()
+}
+
+/// Writes data to path as hex encoded bincode, the format Dbcs are pasted in.
+fn write_hex_file<T: Serialize>(path: &Path, data: &T) -> Result<()> {
+    if path.exists() && readline_prompt("File exists.  Overwrite? [y/n]: ")? != "y" {
+        return Err(miette!("Not overwriting {}", path.display()));
+    }
+    let hex = encode(&bincode::serialize(data).into_diagnostic()?);
+    std::fs::write(path, hex + "\n").into_diagnostic()
+}
+
+fn read_hex_file<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let hex = std::fs::read_to_string(path).into_diagnostic()?;
+    from_le_hex(hex.trim())
+}
+
 // /// Deserialize anything deserializable from big endian bytes
 // fn from_be_bytes<T: for<'de> Deserialize<'de>>(b: &[u8]) -> Result<T> {
 //     let bb = big_endian_bytes_to_bincode_bytes(b.to_vec());
@@ -824,3 +4577,488 @@ fn unset_tty_icanon() -> Result<(RawFd, Termios)> {
     tcsetattr(tty_fd, TCSADRAIN, &termios_new).into_diagnostic()?;
     Ok((tty_fd, termios_old))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_indexes_cases() {
+        let cases: &[(&str, usize, Option<Vec<usize>>)] = &[
+            ("", 3, None),
+            (" , ", 3, None),
+            ("0", 0, None),
+            ("0", 1, Some(vec![0])),
+            ("0,2 1", 3, Some(vec![0, 1, 2])),
+            ("2,2,0", 3, Some(vec![0, 2])),
+            ("3", 3, None),
+            ("-1", 3, None),
+            ("a", 3, None),
+            ("99999999999999999999999", 3, None),
+        ];
+        for (list, len, expected) in cases.iter() {
+            assert_eq!(parse_indexes(list, *len).ok(), *expected, "list: {}", list);
+        }
+    }
+
+    #[test]
+    fn parse_denominations_cases() {
+        let cases: &[(&str, Option<Vec<Amount>>)] = &[
+            ("", Some(vec![])),
+            ("50", Some(vec![50])),
+            ("100x3,50,10x2", Some(vec![100, 100, 100, 50, 10, 10])),
+            ("5,,5", Some(vec![5, 5])),
+            ("0", None),
+            ("10x0", None),
+            ("x3", None),
+            ("10x", None),
+            ("abc", None),
+            ("1x18446744073709551615", None),
+            ("1x101", None),
+            ("1x100", Some(vec![1; 100])),
+            ("1x100,1", None),
+            ("18446744073709551615,1", None),
+            ("9223372036854775808x2", None),
+        ];
+        for (list, expected) in cases.iter() {
+            assert_eq!(parse_denominations(list).ok(), *expected, "list: {}", list);
+        }
+    }
+
+    #[test]
+    fn power_of_two_denominations_cases() {
+        let cases: &[(Amount, Vec<Amount>)] = &[
+            (0, vec![]),
+            (1, vec![1]),
+            (6, vec![4, 2]),
+            (13, vec![8, 4, 1]),
+        ];
+        for (amount, expected) in cases.iter() {
+            assert_eq!(power_of_two_denominations(*amount), *expected);
+        }
+        assert_eq!(power_of_two_denominations(Amount::MAX).len(), 64);
+    }
+
+    #[test]
+    fn cipher_round_trip() -> Result<()> {
+        let cipher = WalletCipher::new("correct horse")?;
+        let encrypted = cipher.encrypt(b"wallet bytes")?;
+        assert_eq!(encrypted.salt, cipher.salt);
+        assert_ne!(encrypted.ciphertext, b"wallet bytes");
+        assert_eq!(cipher.decrypt(&encrypted)?, b"wallet bytes");
+
+        let derived = WalletCipher::derive("correct horse", cipher.salt)?;
+        assert_eq!(derived.decrypt(&encrypted)?, b"wallet bytes");
+
+        let sealed = cipher.seal(b"record")?;
+        assert_eq!(cipher.open(&sealed)?, b"record");
+        assert!(cipher.open(&sealed[..11]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cipher_wrong_passphrase() -> Result<()> {
+        let cipher = WalletCipher::new("correct horse")?;
+        assert!(cipher.matches("correct horse")?);
+        assert!(!cipher.matches("battery staple")?);
+
+        let wrong = WalletCipher::derive("battery staple", cipher.salt)?;
+        assert!(wrong.decrypt(&cipher.encrypt(b"wallet bytes")?).is_err());
+        assert!(wrong.open(&cipher.seal(b"record")?).is_err());
+        Ok(())
+    }
+
+    fn random_secret_key() -> SecretKey {
+        SecretKeySet::random(0, &mut rng::thread_rng()).secret_key()
+    }
+
+    /// Layout of a version 0 wallet, for writing one.  The dbcs map is
+    /// left empty, so its value type does not matter.
+    #[derive(Serialize)]
+    struct WalletV0Out {
+        dbcs: HashMap<[u8; 32], ()>,
+        keys: KeyRing,
+    }
+
+    fn wallet_v0_bytes(secret_key: &SecretKey) -> Result<Vec<u8>> {
+        let mut keys = KeyRing::new();
+        keys.insert(secret_key.public_key(), SerdeSecret(secret_key.clone()));
+        bincode::serialize(&WalletV0Out {
+            dbcs: HashMap::new(),
+            keys,
+        })
+        .into_diagnostic()
+    }
+
+    #[test]
+    fn migrate_plain_v0() -> Result<()> {
+        let secret_key = random_secret_key();
+        let (wallet, cipher) = decode_wallet(&wallet_v0_bytes(&secret_key)?, "passphrase")?;
+        assert!(wallet.keys.contains_key(&secret_key.public_key()));
+        assert!(wallet.dbcs.is_empty());
+        assert!(cipher.matches("passphrase")?);
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_encrypted_v0() -> Result<()> {
+        let secret_key = random_secret_key();
+        let cipher = WalletCipher::new("passphrase")?;
+        let encrypted = cipher.encrypt(&wallet_v0_bytes(&secret_key)?)?;
+        let bytes = bincode::serialize(&encrypted).into_diagnostic()?;
+
+        let (wallet, migrated) = decode_wallet(&bytes, "passphrase")?;
+        assert!(wallet.keys.contains_key(&secret_key.public_key()));
+        assert_eq!(migrated.salt, cipher.salt);
+        assert!(decode_wallet(&bytes, "wrong").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_wallet_rejects_bad_header() -> Result<()> {
+        let cipher = WalletCipher::new("passphrase")?;
+        let wallet = Wallet {
+            next_key_index: 7,
+            ..Default::default()
+        };
+        let bytes = encode_wallet(&wallet, &cipher)?;
+        assert_eq!(decode_wallet(&bytes, "passphrase")?.0.next_key_index, 7);
+        assert!(decode_wallet(&bytes, "wrong").is_err());
+
+        // without the magic, the file is read as version 0, and fails.
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(decode_wallet(&bad_magic, "passphrase").is_err());
+        assert!(decode_wallet(b"not a wallet", "passphrase").is_err());
+
+        let mut unknown_version = WALLET_MAGIC.to_vec();
+        unknown_version.extend_from_slice(&(WALLET_FORMAT_VERSION + 1).to_le_bytes());
+        unknown_version.extend_from_slice(&bytes[WALLET_MAGIC.len() + 2..]);
+        let err = decode_wallet(&unknown_version, "passphrase").err().unwrap();
+        assert!(err.to_string().contains("is not supported"));
+
+        let truncated = &bytes[..WALLET_MAGIC.len() + 1];
+        let err = decode_wallet(truncated, "passphrase").err().unwrap();
+        assert!(err.to_string().contains("truncated"));
+        Ok(())
+    }
+
+    #[test]
+    fn derive_secret_key_is_deterministic() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let seed = bip39::Mnemonic::parse_normalized(phrase)
+            .unwrap()
+            .to_seed_normalized("");
+
+        let expected = [
+            (
+                6,
+                "42cadeb2d4163e182be628efb8a741a560fe09e01289bb6c84c380ef594ee053",
+            ),
+            (
+                7,
+                "5bd9329f9d1cc6f016320644c1ef3d43f90ae5843f316c2f056753e788ebf869",
+            ),
+        ];
+        for (index, hex) in expected.iter() {
+            assert_eq!(encode(derive_secret_key(&seed, *index).to_bytes()), *hex);
+        }
+        assert_eq!(
+            derive_secret_key(&seed, 0).public_key(),
+            derive_secret_key(&seed, 0).public_key()
+        );
+        assert_ne!(
+            derive_secret_key(&seed, 0).public_key(),
+            derive_secret_key(&seed, 1).public_key()
+        );
+    }
+
+    /// A directory for one test's files, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "sn_dbc_wallet_test_{}_{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn seeded_wallet() -> Wallet {
+        Wallet {
+            seed: Some(vec![1; 64]),
+            ..Default::default()
+        }
+    }
+
+    fn file_len(path: &Path) -> Result<u64> {
+        Ok(std::fs::metadata(path).into_diagnostic()?.len())
+    }
+
+    #[test]
+    fn memory_store_round_trip() -> Result<()> {
+        let mut store = MemoryStore::default();
+        assert!(!store.exists());
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        let mut wallet = seeded_wallet();
+        store.save(&mut wallet)?;
+        assert!(store.exists());
+
+        let secret_key = wallet.new_key()?;
+        store.commit(&mut wallet)?;
+        assert!(wallet.changes.is_empty());
+
+        let loaded = store.load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 1);
+        assert!(loaded.keys.contains_key(&secret_key.public_key()));
+        assert!(store.load("wrong").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn file_store_round_trip() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat");
+        let journal_path = suffixed_path(&path, "journal");
+        let header_len = (JOURNAL_MAGIC.len() + 32) as u64;
+
+        let mut store = FileStore::new(&path, 1);
+        assert!(!store.exists());
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        let mut wallet = seeded_wallet();
+        store.save(&mut wallet)?;
+        assert!(store.exists());
+        assert_eq!(file_len(&journal_path)?, header_len);
+
+        // a commit is appended to the journal, and the wallet file is untouched.
+        let wallet_bytes = std::fs::read(&path).into_diagnostic()?;
+        let secret_key = wallet.new_key()?;
+        store.commit(&mut wallet)?;
+        assert!(wallet.changes.is_empty());
+        assert_eq!(std::fs::read(&path).into_diagnostic()?, wallet_bytes);
+        assert!(file_len(&journal_path)? > header_len);
+
+        let loaded = FileStore::new(&path, 1).load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 1);
+        assert!(loaded.keys.contains_key(&secret_key.public_key()));
+
+        // save folds the journal into the wallet file.
+        store.save(&mut wallet)?;
+        assert_eq!(file_len(&journal_path)?, header_len);
+        let loaded = FileStore::new(&path, 1).load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 1);
+        assert!(loaded.keys.contains_key(&secret_key.public_key()));
+        Ok(())
+    }
+
+    #[test]
+    fn journal_discards_partial_entry() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat");
+        let journal_path = suffixed_path(&path, "journal");
+
+        let mut store = FileStore::new(&path, 1);
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        let mut wallet = seeded_wallet();
+        store.save(&mut wallet)?;
+        wallet.new_key()?;
+        store.commit(&mut wallet)?;
+        let one_entry_len = file_len(&journal_path)?;
+        wallet.new_key()?;
+        store.commit(&mut wallet)?;
+
+        // as if we crashed while appending the second entry.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&journal_path)
+            .into_diagnostic()?;
+        file.set_len(file_len(&journal_path)? - 3)
+            .into_diagnostic()?;
+        drop(file);
+
+        let mut store = FileStore::new(&path, 1);
+        let loaded = store.load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 1);
+        assert_eq!(loaded.keys.len(), 1);
+        assert_eq!(file_len(&journal_path)?, one_entry_len);
+
+        // later entries are appended after the last complete one.
+        let mut wallet = loaded;
+        wallet.new_key()?;
+        store.commit(&mut wallet)?;
+        let loaded = FileStore::new(&path, 1).load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 2);
+        assert_eq!(loaded.keys.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn journal_ignored_if_stale() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat");
+        let journal_path = suffixed_path(&path, "journal");
+
+        let mut store = FileStore::new(&path, 1);
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        let mut wallet = seeded_wallet();
+        store.save(&mut wallet)?;
+        wallet.new_key()?;
+        store.commit(&mut wallet)?;
+        let stale = std::fs::read(&journal_path).into_diagnostic()?;
+
+        // a journal started from an older wallet file is not replayed.
+        wallet.new_key()?;
+        store.save(&mut wallet)?;
+        std::fs::write(&journal_path, &stale).into_diagnostic()?;
+
+        let loaded = FileStore::new(&path, 1).load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 2);
+        assert_eq!(loaded.keys.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn journal_corrupt_entry_falls_back_to_backup() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat");
+        let journal_path = suffixed_path(&path, "journal");
+
+        let mut store = FileStore::new(&path, 1);
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        let mut wallet = seeded_wallet();
+        store.save(&mut wallet)?;
+        wallet.new_key()?;
+        store.save(&mut wallet)?;
+        wallet.new_key()?;
+        store.commit(&mut wallet)?;
+
+        // a complete entry that fails to decrypt is not a crash artifact.
+        let mut journal = std::fs::read(&journal_path).into_diagnostic()?;
+        *journal.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&journal_path, &journal).into_diagnostic()?;
+
+        let loaded = FileStore::new(&path, 1).load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 0);
+        assert!(loaded.keys.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn journal_compacts_when_full() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat");
+        let journal_path = suffixed_path(&path, "journal");
+        let header_len = (JOURNAL_MAGIC.len() + 32) as u64;
+
+        let mut store = FileStore::new(&path, 1);
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        let mut wallet = seeded_wallet();
+        store.save(&mut wallet)?;
+        let wallet_bytes = std::fs::read(&path).into_diagnostic()?;
+
+        store.journal_entries = JOURNAL_COMPACT_ENTRIES;
+        wallet.new_key()?;
+        store.commit(&mut wallet)?;
+        assert_eq!(store.journal_entries, 0);
+        assert_eq!(file_len(&journal_path)?, header_len);
+        assert_ne!(std::fs::read(&path).into_diagnostic()?, wallet_bytes);
+        // compacting does not rotate the backups.
+        assert!(!suffixed_path(&path, "1").exists());
+
+        let loaded = FileStore::new(&path, 1).load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 1);
+        assert_eq!(loaded.keys.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn sled_store_round_trip() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat.db");
+        let mut wallet = seeded_wallet();
+
+        let mut store = SledStore::open(&path)?;
+        assert!(!store.exists());
+        store.set_cipher(WalletCipher::new("passphrase")?);
+        store.save(&mut wallet)?;
+        let first_key = wallet.new_key()?.public_key();
+        store.commit(&mut wallet)?;
+        assert!(wallet.changes.is_empty());
+        drop(store);
+
+        let mut store = SledStore::open(&path)?;
+        assert!(store.exists());
+        let loaded = store.load("passphrase")?;
+        assert_eq!(loaded.next_key_index, 1);
+        assert!(loaded.keys.contains_key(&first_key));
+
+        // records are sealed with a fresh nonce, so one that is rewritten
+        // changes.  Unless the passphrase changed, save only writes changes.
+        let record = |store: &SledStore| store.keys.get(&first_key.to_bytes()[..]).unwrap();
+        let first_record = record(&store);
+        assert!(first_record.is_some());
+        let second_key = wallet.new_key()?.public_key();
+        store.save(&mut wallet)?;
+        assert_eq!(record(&store), first_record);
+
+        store.set_cipher(WalletCipher::new("new passphrase")?);
+        store.save(&mut wallet)?;
+        assert_ne!(record(&store), first_record);
+        drop(store);
+
+        let mut store = SledStore::open(&path)?;
+        assert!(store.load("passphrase").is_err());
+        let loaded = store.load("new passphrase")?;
+        assert_eq!(loaded.next_key_index, 2);
+        assert!(loaded.keys.contains_key(&first_key));
+        assert!(loaded.keys.contains_key(&second_key));
+        Ok(())
+    }
+
+    #[test]
+    fn passwd_rekeys_backups() -> Result<()> {
+        let dir = TestDir::new();
+        let path = dir.path("wallet.dat");
+        let mut store = FileStore::new(&path, 3);
+        store.set_cipher(WalletCipher::new("old")?);
+
+        let mut wallet = Wallet::default();
+        store.save(&mut wallet)?;
+        std::fs::write(suffixed_path(&path, "2"), b"not a wallet").into_diagnostic()?;
+        wallet.next_key_index = 1;
+        store.save(&mut wallet)?;
+
+        store.set_cipher(WalletCipher::new("new")?);
+        wallet.next_key_index = 2;
+        store.save(&mut wallet)?;
+
+        for n in 1..=2 {
+            let backup_path = suffixed_path(&path, &n.to_string());
+            assert!(FileStore::load_file(&backup_path, "old").is_err());
+            let (backup, _, _) = FileStore::load_file(&backup_path, "new")?;
+            assert_eq!(backup.next_key_index, 2 - n as u64);
+        }
+        assert!(!suffixed_path(&path, "3").exists());
+
+        let mut store = FileStore::new(&path, 3);
+        assert!(store.load("old").is_err());
+        assert_eq!(store.load("new")?.next_key_index, 2);
+        Ok(())
+    }
+}
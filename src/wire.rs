@@ -41,6 +41,8 @@ pub mod spentbook {
             pub enum Msg {
                 Discover,
                 LogSpent(sn_dbc::KeyImage, sn_dbc::RingCtTransaction),
+                /// request up to this many random decoys, to use in an input's ring.
+                Decoys(usize),
             }
         }
 
@@ -53,6 +55,7 @@ pub mod spentbook {
                     std::collections::BTreeMap<xor_name::XorName, std::net::SocketAddr>,
                 ),
                 LogSpent(super::Result<sn_dbc::SpentProofShare>),
+                Decoys(super::Result<Vec<sn_dbc::DecoyInput>>),
             }
         }
 
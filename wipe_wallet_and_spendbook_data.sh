@@ -1 +1,9 @@
+# spentbook and wallet files
 rm -f .*.dat *.dat
+# rotated wallet backups, eg .wallet.dat.1
+rm -f .*.dat.[0-9]* *.dat.[0-9]*
+# wallet journals, lock files and partly written temp files
+rm -f .*.dat.journal *.dat.journal .*.dat.lock *.dat.lock
+rm -f .*.dat.tmp *.dat.tmp .*.dat.journal.tmp *.dat.journal.tmp
+# sled wallet databases (--wallet-store sled)
+rm -rf .*.dat.db *.dat.db